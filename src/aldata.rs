@@ -0,0 +1,219 @@
+use std::{error::Error, fmt};
+
+use base64::{
+  alphabet,
+  engine::{general_purpose, DecodePaddingMode, GeneralPurpose},
+  Engine as _,
+};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+const VIDEO_URL_PREFIX: &str = "vid_url_";
+// how many nested encodings to peel off before giving up
+const MAX_LAYERS: usize = 3;
+
+const STANDARD_LENIENT: GeneralPurpose = GeneralPurpose::new(
+  &alphabet::STANDARD,
+  general_purpose::GeneralPurposeConfig::new()
+    .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+const URL_SAFE_LENIENT: GeneralPurpose = GeneralPurpose::new(
+  &alphabet::URL_SAFE,
+  general_purpose::GeneralPurposeConfig::new()
+    .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+#[derive(Debug)]
+pub enum DecodeError {
+  // none of the base64 variants accepted the input
+  NotBase64(String),
+  // decoded fine, but no layer turned into a JSON object
+  NotJson(Vec<String>),
+}
+
+impl fmt::Display for DecodeError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      DecodeError::NotBase64(e) => {
+        write!(f, "_aldata is not base64 (standard or url-safe): {}", e)
+      }
+      DecodeError::NotJson(tried) => {
+        write!(
+          f,
+          "_aldata decodes but not to JSON, tried {}",
+          tried.join(", ")
+        )
+      }
+    }
+  }
+}
+
+impl Error for DecodeError {}
+
+// A quality offered by the player, e.g. "1080" with its `video_data` url
+pub struct VideoUrl {
+  pub quality: String,
+  pub url: String,
+}
+
+// The decoded `_aldata` object, only the `vid_url_<quality>` keys matter
+#[derive(Deserialize)]
+#[serde(from = "Map<String, Value>")]
+pub struct PlayerData {
+  // highest quality first
+  pub video_urls: Vec<VideoUrl>,
+}
+
+impl From<Map<String, Value>> for PlayerData {
+  fn from(map: Map<String, Value>) -> PlayerData {
+    let mut video_urls = Vec::new();
+
+    for (key, value) in map {
+      if let (Some(quality), Some(url)) =
+        (key.strip_prefix(VIDEO_URL_PREFIX), value.as_str())
+      {
+        if !url.is_empty() {
+          video_urls.push(VideoUrl {
+            quality: quality.to_string(),
+            url: url.to_string(),
+          })
+        }
+      }
+    }
+
+    video_urls.sort_by_key(|v| {
+      std::cmp::Reverse(v.quality.parse::<u32>().unwrap_or(0))
+    });
+    PlayerData { video_urls }
+  }
+}
+
+impl PlayerData {
+  pub fn from_json(json: &[u8]) -> serde_json::Result<PlayerData> {
+    serde_json::from_slice(json)
+  }
+
+  pub fn qualities(&self) -> Vec<String> {
+    self.video_urls.iter().map(|v| v.quality.clone()).collect()
+  }
+
+  pub fn video_url(&self, quality: &str) -> Option<&str> {
+    self
+      .video_urls
+      .iter()
+      .find(|v| v.quality == quality)
+      .map(|v| v.url.as_str())
+  }
+}
+
+fn decode_base64(input: &str) -> Result<Vec<u8>, String> {
+  let cleaned: String =
+    input.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+
+  STANDARD_LENIENT
+    .decode(&cleaned)
+    .or_else(|_| URL_SAFE_LENIENT.decode(&cleaned))
+    .map_err(|e| e.to_string())
+}
+
+fn is_json_object(bytes: &[u8]) -> bool {
+  let trimmed = bytes.trim_ascii_start();
+  trimmed.starts_with(b"{")
+    && serde_json::from_slice::<Map<String, Value>>(bytes).is_ok()
+}
+
+// a single byte xor key that turns `bytes` into a JSON object, if any
+fn find_xor_key(bytes: &[u8]) -> Option<u8> {
+  (1..=u8::MAX).find(|key| {
+    let xored: Vec<u8> = bytes.iter().map(|b| b ^ key).collect();
+    is_json_object(&xored)
+  })
+}
+
+// Decodes `_aldata` into JSON bytes. Handles standard and url-safe base64,
+// missing padding, embedded whitespace, nested base64 and single byte xor.
+pub fn decode(encoded: &str) -> Result<Vec<u8>, DecodeError> {
+  let mut tried = Vec::new();
+  let mut bytes = decode_base64(encoded).map_err(DecodeError::NotBase64)?;
+
+  for layer in 0..MAX_LAYERS {
+    if is_json_object(&bytes) {
+      return Ok(bytes);
+    }
+    tried.push(format!("json (layer {})", layer));
+
+    if let Some(key) = find_xor_key(&bytes) {
+      return Ok(bytes.iter().map(|b| b ^ key).collect());
+    }
+    tried.push(format!("xor (layer {})", layer));
+
+    let nested = match std::str::from_utf8(&bytes).map(decode_base64) {
+      Ok(Ok(nested)) => nested,
+      _ => break,
+    };
+    tried.push(format!("base64 (layer {})", layer + 1));
+    bytes = nested;
+  }
+
+  Err(DecodeError::NotJson(tried))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // '?' and '>' encode to '/' and '+' in standard base64
+  const JSON: &str = r#"{"vid_url_720":"video.example/v/720?a=>>>"}"#;
+
+  fn decoded(encoded: &str) -> String {
+    String::from_utf8(decode(encoded).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn standard() {
+    assert_eq!(decoded(&general_purpose::STANDARD.encode(JSON)), JSON);
+  }
+
+  #[test]
+  fn url_safe() {
+    let encoded = general_purpose::URL_SAFE.encode(JSON);
+    assert!(encoded.contains(['-', '_']));
+    assert_eq!(decoded(&encoded), JSON);
+  }
+
+  #[test]
+  fn missing_padding() {
+    let encoded = general_purpose::STANDARD_NO_PAD.encode(JSON);
+    assert_ne!(encoded.len() % 4, 0);
+    assert_eq!(decoded(&encoded), JSON);
+  }
+
+  #[test]
+  fn embedded_whitespace() {
+    let encoded = general_purpose::STANDARD.encode(JSON);
+    let (head, tail) = encoded.split_at(20);
+    assert_eq!(decoded(&format!(" {}\n\t{} \r\n", head, tail)), JSON);
+  }
+
+  #[test]
+  fn nested_base64() {
+    let inner = general_purpose::STANDARD.encode(JSON);
+    assert_eq!(decoded(&general_purpose::URL_SAFE.encode(inner)), JSON);
+  }
+
+  #[test]
+  fn single_byte_xor() {
+    let xored: Vec<u8> = JSON.bytes().map(|b| b ^ 0x5a).collect();
+    assert_eq!(decoded(&general_purpose::STANDARD.encode(xored)), JSON);
+  }
+
+  #[test]
+  fn errors() {
+    assert!(matches!(
+      decode("not base64!"),
+      Err(DecodeError::NotBase64(_))
+    ));
+    let text = general_purpose::STANDARD.encode("plain text, no json");
+    assert!(matches!(decode(&text), Err(DecodeError::NotJson(_))));
+  }
+}
@@ -1,27 +1,205 @@
 use std::{
+  collections::BTreeSet,
+  error::Error,
+  fmt,
   fs::{self, File},
   io::{self, Write},
-  sync::Arc,
+  path::{Path, PathBuf},
+  sync::{Mutex, RwLock},
+  time::{Duration, Instant},
 };
 
-use base64::{engine::general_purpose, Engine as _};
-use log::{debug, info, warn};
+use futures::{FutureExt, StreamExt};
+use log::{debug, error, info, warn};
 use regex::Regex;
-use reqwest::Client;
+use reqwest::{header, Client, RequestBuilder, Response, StatusCode};
 use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::sync::Semaphore;
 
-use crate::{cli::print_progress, AsyncResult};
+#[cfg(feature = "js")]
+use crate::js::{self, PlayerScriptResult};
+use crate::{
+  aldata::{self, PlayerData},
+  aria2::{Aria2Downloader, Aria2Options},
+  checksum,
+  cli::print_progress,
+  config,
+  downloader::{self, HttpDownloader, SegmentDownloader, SegmentJob},
+  hls::{self, ByteRange, MediaPlaylist, Rendition, RenditionType},
+  http,
+  repair::{self, MappedSegment, SegmentMap},
+  session, verify, video, AsyncResult, FileName,
+};
 
-pub const HOST: &str = "https://anilife.live";
+pub const DEFAULT_HOST: &str = "https://anilife.live";
 pub const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/115.0.0.0 Safari/537.36";
 
-// const HLS_ENC_TAG: &str = "#EXT-X-KEY";
-const HLS_SEG_TAG: &str = "#EXTINF";
+// The current host first, then fallback mirrors in the order they are tried
+static HOSTS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+// set by the redirect policy when the current host answers with 301/308
+static MOVED_HOST: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_hosts(hosts: Vec<String>) {
+  let hosts = hosts
+    .into_iter()
+    .map(|h| h.trim_end_matches('/').to_string())
+    .filter(|h| !h.is_empty())
+    .fold(Vec::new(), |mut hosts: Vec<String>, h| {
+      if !hosts.contains(&h) {
+        hosts.push(h);
+      }
+      hosts
+    });
+  *HOSTS.write().unwrap() = hosts;
+}
+
+pub fn hosts() -> Vec<String> {
+  let hosts = HOSTS.read().unwrap();
+  if hosts.is_empty() {
+    vec![DEFAULT_HOST.to_string()]
+  } else {
+    hosts.clone()
+  }
+}
+
+pub fn host() -> String {
+  hosts().remove(0)
+}
 
 pub fn build_url(path: &str) -> String {
-  HOST.to_string() + path
+  host() + path
+}
+
+// Called from the client's redirect policy, `from` and `to` are origins
+pub fn record_permanent_redirect(from: &str, to: &str) {
+  if from == host() {
+    *MOVED_HOST.lock().unwrap() = Some(to.to_string());
+  }
+}
+
+// Switches to (and remembers) a host the current one permanently moved to
+fn follow_moved_host() {
+  let Some(moved) = MOVED_HOST.lock().unwrap().take() else {
+    return;
+  };
+
+  let mut hosts = hosts();
+  info!("{} moved permanently to {}", hosts[0], moved);
+  hosts.insert(0, moved.clone());
+  set_hosts(hosts);
+  config::remember_host(&moved);
+}
+
+// markers of Cloudflare style interstitials instead of the real page
+const CHALLENGE_MARKERS: [&str; 5] = [
+  "challenge-platform",
+  "cf-chl-",
+  "cf-browser-verification",
+  "<title>Just a moment...</title>",
+  "Attention Required! | Cloudflare",
+];
+
+struct Page {
+  url: String,
+  status: StatusCode,
+  html: String,
+  challenge: bool,
+}
+
+impl Page {
+  async fn read(res: Response) -> AsyncResult<Page> {
+    let url = res.url().to_string();
+    let status = res.status();
+    let mitigated = res.headers().contains_key("cf-mitigated");
+    let html = http::read_text(res).await?;
+    let challenge = mitigated
+      || (matches!(status.as_u16(), 403 | 429 | 503)
+        && CHALLENGE_MARKERS.iter().any(|m| html.contains(m)));
+
+    Ok(Page {
+      url,
+      status,
+      html,
+      challenge,
+    })
+  }
+
+  fn ensure_not_challenged(&self) -> AsyncResult<()> {
+    if !self.challenge {
+      return Ok(());
+    }
+
+    error!(
+      "{} answered with a bot challenge ({})",
+      self.url, self.status
+    );
+    Err(
+      format!(
+        "blocked by a bot challenge at {}, solve it in a browser and pass its \
+         cookies with --cookies <cookies.txt> and its user agent with \
+         --user-agent",
+        self.url
+      )
+      .into(),
+    )
+  }
+}
+
+// Sends `req` and reads the page, logging in again and retrying once when a
+// stored session turns out to be expired
+async fn send_page(client: &Client, req: RequestBuilder) -> AsyncResult<Page> {
+  let retry = req.try_clone();
+  let page = Page::read(http::send(client, req).await?).await?;
+
+  match retry {
+    Some(retry) if session::is_expired(&page.url, page.status) => {
+      session::relogin(client).await?;
+      Page::read(http::send(client, retry).await?).await
+    }
+    _ => Ok(page),
+  }
+}
+
+// GETs `path` from the current host, rotating through the mirrors when a host
+// is unreachable or failing
+async fn get_page(client: &Client, path: &str) -> AsyncResult<Page> {
+  let hosts = hosts();
+  let mut last_error: Box<dyn Error> = "no hosts configured".into();
+
+  for (index, host) in hosts.iter().enumerate() {
+    let url = format!("{}{}", host, path);
+    match send_page(client, client.get(&url)).await {
+      Ok(page) if !page.status.is_server_error() => {
+        if index > 0 {
+          warn!("{} is failing, switching to mirror {}", hosts[0], host);
+          let mut rotated = hosts.clone();
+          rotated.rotate_left(index);
+          set_hosts(rotated);
+        }
+        follow_moved_host();
+        page.ensure_not_challenged()?;
+        return Ok(page);
+      }
+      Ok(page) => {
+        page.ensure_not_challenged()?;
+        warn!("{} answered {}", url, page.status);
+        last_error = format!("{} answered {}", url, page.status).into();
+      }
+      Err(e) => {
+        warn!("{} failed: {}", url, e);
+        last_error = e;
+      }
+    }
+  }
+
+  Err(last_error)
+}
+
+// Player pages live on the site itself, so accept any known host
+fn player_url_pattern() -> String {
+  let hosts: Vec<String> = hosts().iter().map(|h| regex::escape(h)).collect();
+  format!(r#"(?<path>(?:{})\/h\/live\?p=.+)""#, hosts.join("|"))
 }
 
 pub struct LifeAnimeInfo {
@@ -42,8 +220,7 @@ pub struct LifeEpisodeInfo {
 }
 
 pub async fn get_top(client: &Client) -> AsyncResult<Vec<LifeAnimeInfo>> {
-  let url = build_url("/top20");
-  let html = client.get(&url).send().await?.text().await?;
+  let html = get_page(client, "/top20").await?.html;
   let document = Html::parse_document(&html);
 
   let selector = Selector::parse(".bsx").unwrap();
@@ -64,7 +241,7 @@ pub async fn get_top(client: &Client) -> AsyncResult<Vec<LifeAnimeInfo>> {
       let url_str = url_element.unwrap().value().attr("href").unwrap_or("");
       let url = build_url(url_str);
       let title = title_element.unwrap().inner_html();
-      let id = url.split('/').last().unwrap_or("\0").to_string();
+      let id = url.split('/').next_back().unwrap_or("\0").to_string();
 
       LifeAnimeInfo { id, url, title }
     })
@@ -74,12 +251,11 @@ pub async fn get_top(client: &Client) -> AsyncResult<Vec<LifeAnimeInfo>> {
 }
 
 pub async fn get_new(client: &Client) -> AsyncResult<Vec<LifeAnimeInfo>> {
-  let url = build_url("/");
-  let html = client.get(&url).send().await?.text().await?;
+  let html = get_page(client, "/").await?.html;
   let document = Html::parse_document(&html);
   let new_selector = Selector::parse(".listupd").unwrap();
 
-  let anime_lists = document.select(&new_selector).skip(1).next().unwrap();
+  let anime_lists = document.select(&new_selector).nth(1).unwrap();
 
   let selector = Selector::parse(".bsx").unwrap();
   let a_selector = Selector::parse("a").unwrap();
@@ -99,7 +275,7 @@ pub async fn get_new(client: &Client) -> AsyncResult<Vec<LifeAnimeInfo>> {
       let url_str = url_element.unwrap().value().attr("href").unwrap_or("");
       let url = build_url(url_str);
       let title = title_element.unwrap().inner_html();
-      let id = url.split('/').last().unwrap_or("\0").to_string();
+      let id = url.split('/').next_back().unwrap_or("\0").to_string();
 
       LifeAnimeInfo { id, url, title }
     })
@@ -113,8 +289,7 @@ pub async fn search(
   query: &String,
 ) -> AsyncResult<(Vec<LifeAnimeInfo>, String)> {
   let search_path = format!("/search?keyword={}", query);
-  let url = build_url(&search_path);
-  let html = client.get(&url).send().await?.text().await?;
+  let Page { url, html, .. } = get_page(client, &search_path).await?;
   let document = Html::parse_document(&html);
 
   let selector = Selector::parse(".bsx").unwrap();
@@ -135,7 +310,7 @@ pub async fn search(
       let url_str = url_element.unwrap().value().attr("href").unwrap_or("");
       let url = build_url(url_str);
       let title = title_element.unwrap().inner_html();
-      let id = url.split('/').last().unwrap_or("\0").to_string();
+      let id = url.split('/').next_back().unwrap_or("\0").to_string();
 
       LifeAnimeInfo { id, url, title }
     })
@@ -146,10 +321,11 @@ pub async fn search(
 
 pub async fn get_anime(client: &Client, id: &String) -> AsyncResult<LifeAnime> {
   let anime_path = format!("/detail/id/{}", id);
-  let url = build_url(&anime_path);
-  let res = client.get(url).send().await?;
-  let anime_url = res.url().to_string();
-  let html = res.text().await?;
+  let Page {
+    url: anime_url,
+    html,
+    ..
+  } = get_page(client, &anime_path).await?;
   let document = Html::parse_document(&html);
 
   let anime_title_selector = Selector::parse(".entry-title").unwrap();
@@ -199,20 +375,143 @@ pub async fn get_anime(client: &Client, id: &String) -> AsyncResult<LifeAnime> {
   })
 }
 
-pub async fn get_episode_hls(
+const ALDATA_PATTERN: &str = r#"var _aldata = '(.+?)'"#;
+
+#[derive(Clone, Default)]
+pub struct ResolveOptions {
+  pub quality: Option<String>,
+  // directory to save every page and response of the resolution chain to
+  pub debug_dump: Option<String>,
+}
+
+impl ResolveOptions {
+  // Options to find a stream resolved with `quality` again: a `vid_url_*`
+  // quality is pinned, otherwise the caller's choice applies
+  pub fn pinned(&self, quality: &Option<String>) -> ResolveOptions {
+    ResolveOptions {
+      quality: quality.clone().or_else(|| self.quality.clone()),
+      ..self.clone()
+    }
+  }
+}
+
+// in resolution order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResolveStage {
+  EpisodePage,
+  PlayerPage,
+  PlayerData,
+  VideoData,
+  Playlist,
+}
+
+// Which step of episode page -> player -> `_aldata` -> `video_data` ->
+// playlist failed, and why. When every source of an episode failed,
+// `attempts` has each source with its own error.
+pub struct ResolveError {
+  pub stage: ResolveStage,
+  pub reason: String,
+  pub attempts: Vec<(String, ResolveError)>,
+}
+
+impl ResolveError {
+  fn new(stage: ResolveStage, reason: impl Into<String>) -> ResolveError {
+    ResolveError {
+      stage,
+      reason: reason.into(),
+      attempts: Vec::new(),
+    }
+  }
+
+  fn boxed(stage: ResolveStage, reason: impl Into<String>) -> Box<dyn Error> {
+    Box::new(ResolveError::new(stage, reason))
+  }
+
+  // `e` itself when it is a resolve error, otherwise a failure at `stage`
+  fn from_error(e: Box<dyn Error>, stage: ResolveStage) -> ResolveError {
+    match e.downcast::<ResolveError>() {
+      Ok(e) => *e,
+      Err(e) => ResolveError::new(stage, e.to_string()),
+    }
+  }
+}
+
+impl fmt::Display for ResolveError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{:?}: {}", self.stage, self.reason)?;
+    for (source, error) in &self.attempts {
+      write!(f, "\n  {}: {}", source, error)?;
+    }
+    Ok(())
+  }
+}
+
+// main prints the error it returns with Debug
+impl fmt::Debug for ResolveError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    fmt::Display::fmt(self, f)
+  }
+}
+
+impl Error for ResolveError {}
+
+// Saves snapshots of the resolution chain of one episode when --debug-dump
+// is given, otherwise does nothing
+struct DebugDump {
+  dir: Option<PathBuf>,
+}
+
+impl DebugDump {
+  fn new(options: &ResolveOptions, episode_url: &str) -> DebugDump {
+    let dir = options.debug_dump.as_ref().map(|dir| {
+      let episode = episode_url
+        .trim_end_matches('/')
+        .split('/')
+        .next_back()
+        .unwrap_or("episode")
+        .to_string()
+        .sanitize();
+      Path::new(dir).join(episode)
+    });
+    DebugDump { dir }
+  }
+
+  fn save(&self, name: &str, content: &[u8]) {
+    let Some(dir) = &self.dir else { return };
+    let path = dir.join(name);
+    let result =
+      fs::create_dir_all(dir).and_then(|_| fs::write(&path, content));
+    match result {
+      Ok(()) => debug!("dumped {}", path.display()),
+      Err(e) => warn!("unable to dump {}: {}", path.display(), e),
+    }
+  }
+}
+
+// Every player mirror linked from the episode page, in page order
+async fn get_episode_players(
   client: &Client,
   url: &String,
   referer: &String,
-) -> AsyncResult<String> {
-  let episode_html = client
-    .get(url)
-    .header("Referer", referer)
-    .send()
-    .await?
-    .text()
-    .await?;
-  let player_url_re =
-    Regex::new(r#"(?<path>https:\/\/anilife.live\/h\/live\?p=.+)""#).unwrap();
+  dump: &DebugDump,
+) -> AsyncResult<Vec<String>> {
+  let stage = ResolveStage::EpisodePage;
+  let req = client.get(url).header("Referer", referer);
+  let page = send_page(client, req).await.map_err(|e| {
+    ResolveError::boxed(ResolveStage::EpisodePage, e.to_string())
+  })?;
+  dump.save("episode.html", page.html.as_bytes());
+  page.ensure_not_challenged()?;
+  if !page.status.is_success() {
+    return Err(ResolveError::boxed(
+      stage,
+      format!("status {}", page.status),
+    ));
+  }
+  let episode_html = page.html;
+
+  let player_url_pattern = player_url_pattern();
+  let player_url_re = Regex::new(&player_url_pattern).unwrap();
   let player_urls: Vec<String> = player_url_re
     .captures_iter(&episode_html)
     .map(|caps| caps["path"].to_string())
@@ -220,175 +519,1287 @@ pub async fn get_episode_hls(
 
   if player_urls.is_empty() {
     warn!("no players");
-    return Err("no players".into());
+    return Err(ResolveError::boxed(
+      stage,
+      format!("no players, regex {} did not match", player_url_pattern),
+    ));
   }
 
-  let player_url = &player_urls[0];
-  let player_html = client
-    .get(player_url)
-    .header("Referer", referer)
-    .send()
-    .await?
-    .text()
-    .await?;
+  Ok(player_urls)
+}
 
-  let aldata_re = Regex::new(r#"var _aldata = '(.+?)'"#).unwrap();
-  let Some((_, [encoded_player_data])) = aldata_re
-      .captures(&player_html)
-      .map(|caps| caps.extract()) else {return Err("_aldata not found".into())};
-  let player_data_json = general_purpose::STANDARD
-    .decode(encoded_player_data)
-    .unwrap();
-  let player_data: Value = serde_json::from_slice(&player_data_json)?;
-  let video_url = match &player_data["vid_url_1080"] {
-    Value::String(url) => format!("https://{}", url),
-    _ => return Err("video url not found".into()),
-  };
+pub enum PlayerSource {
+  Data(PlayerData),
+  // the player scripts handed over a playlist url directly
+  Hls(String),
+}
 
-  let video_data = client
-    .get(video_url)
-    .header("Referer", player_url)
-    .send()
-    .await?
-    .json::<serde_json::Value>()
-    .await?;
+async fn get_player_data(
+  client: &Client,
+  player_url: &str,
+  referer: &String,
+  dump: &DebugDump,
+  player_index: usize,
+) -> AsyncResult<PlayerSource> {
+  let req = client.get(player_url).header("Referer", referer);
+  let page = send_page(client, req).await.map_err(|e| {
+    ResolveError::boxed(ResolveStage::PlayerPage, e.to_string())
+  })?;
+  dump.save(
+    &format!("player-{}.html", player_index),
+    page.html.as_bytes(),
+  );
+  page.ensure_not_challenged()?;
+  if !page.status.is_success() {
+    return Err(ResolveError::boxed(
+      ResolveStage::PlayerPage,
+      format!("status {}", page.status),
+    ));
+  }
+
+  let source = parse_player_page(&page.html, |json| {
+    dump.save(&format!("aldata-{}.json", player_index), json)
+  })?;
+  Ok(source)
+}
 
-  let hls_url = match &video_data[0]["url"] {
-    Value::String(url) => url,
-    _ => return Err("hls url not found".into()),
+// Extracts the player data from a player page, also usable on saved pages.
+// `on_json` sees the decoded `_aldata` before it is parsed.
+pub fn parse_player_page(
+  player_html: &str,
+  on_json: impl FnOnce(&[u8]),
+) -> AsyncResult<PlayerSource> {
+  let aldata_re = Regex::new(ALDATA_PATTERN).unwrap();
+  let encoded_player_data = match aldata_re.captures(player_html) {
+    Some(caps) => caps[1].to_string(),
+    None => match evaluate_player_scripts(player_html)? {
+      ScriptResult::AlData(aldata) => aldata,
+      ScriptResult::Hls(url) => return Ok(PlayerSource::Hls(url)),
+    },
   };
 
-  info!("hsl_url = {}...", &hls_url[..25]);
-  debug!("hsl_url = {}", hls_url);
-  Ok(hls_url.clone())
+  let player_data_json = aldata::decode(&encoded_player_data).map_err(|e| {
+    ResolveError::boxed(ResolveStage::PlayerData, e.to_string())
+  })?;
+  on_json(&player_data_json);
+  let player_data = PlayerData::from_json(&player_data_json).map_err(|e| {
+    ResolveError::boxed(ResolveStage::PlayerData, format!("json: {}", e))
+  })?;
+
+  Ok(PlayerSource::Data(player_data))
 }
 
-struct Segment {
-  index: i32,
-  filename: String,
+#[cfg_attr(not(feature = "js"), allow(dead_code))]
+enum ScriptResult {
+  AlData(String),
+  Hls(String),
 }
 
-pub async fn download_episode(
+#[cfg(feature = "js")]
+fn evaluate_player_scripts(player_html: &str) -> AsyncResult<ScriptResult> {
+  info!("_aldata is not plain text, evaluating player scripts");
+  match js::evaluate_player_page(
+    player_html,
+    ALDATA_PATTERN,
+    &http::user_agent(),
+  ) {
+    Ok(PlayerScriptResult::AlData(aldata)) => Ok(ScriptResult::AlData(aldata)),
+    Ok(PlayerScriptResult::VideoUrl(url)) => Ok(ScriptResult::Hls(url)),
+    Err(e) => Err(ResolveError::boxed(
+      ResolveStage::PlayerPage,
+      format!(
+        "_aldata not found, regex {} did not match and scripts failed: {}",
+        ALDATA_PATTERN, e
+      ),
+    )),
+  }
+}
+
+#[cfg(not(feature = "js"))]
+fn evaluate_player_scripts(_player_html: &str) -> AsyncResult<ScriptResult> {
+  Err(ResolveError::boxed(
+    ResolveStage::PlayerPage,
+    format!(
+      "_aldata not found, regex {} did not match (build with --features js \
+       to evaluate obfuscated player scripts)",
+      ALDATA_PATTERN
+    ),
+  ))
+}
+
+// Qualities of the first player mirror that answers
+pub async fn get_episode_qualities(
   client: &Client,
   url: &String,
-  filename: &String,
-  max_concurrent: usize,
-) -> AsyncResult<()> {
-  fs::create_dir_all("./segments").unwrap();
+  referer: &String,
+  options: &ResolveOptions,
+) -> AsyncResult<Vec<String>> {
+  let dump = DebugDump::new(options, url);
+  let players = get_episode_players(client, url, referer, &dump).await?;
+  let mut last_error = "no players".into();
+  for (player_index, player_url) in players.iter().enumerate() {
+    match get_player_data(client, player_url, referer, &dump, player_index)
+      .await
+    {
+      Ok(PlayerSource::Data(player_data)) => {
+        return Ok(player_data.qualities())
+      }
+      Ok(PlayerSource::Hls(_)) => return Ok(vec!["auto".to_string()]),
+      Err(e) => last_error = e,
+    }
+  }
 
-  let semaphore = Arc::new(Semaphore::new(max_concurrent));
-  let content = client
-    .get(url)
-    .header("Referer", HOST)
-    .send()
-    .await?
-    .text()
-    .await?;
+  Err(last_error)
+}
 
-  let segment_urls = parse_hls(content);
+// A resolved episode stream and where it came from
+pub struct EpisodeStream {
+  pub hls_url: String,
+  pub player_url: String,
+  // the `vid_url_*` quality, none for a script or playlist url
+  pub quality: Option<String>,
+  pub episode_url: String,
+  pub referer: String,
+}
 
-  let mut tasks = vec![];
-  for (idx, segment_url) in segment_urls.iter().enumerate() {
-    let url = segment_url.clone();
-    let semaphore_cloned = semaphore.clone();
-    let task = tokio::spawn(async move {
-      download_segment(idx as i32, url, &semaphore_cloned).await
-    });
-    tasks.push(task);
+// Resolves the episode's HLS url, trying every player mirror and
+// `video_data` entry in turn, using `quality` if pinned and otherwise falling
+// back from the highest quality down
+pub async fn get_episode_hls(
+  client: &Client,
+  url: &String,
+  referer: &String,
+  options: &ResolveOptions,
+) -> AsyncResult<EpisodeStream> {
+  let dump = DebugDump::new(options, url);
+  let player_urls = get_episode_players(client, url, referer, &dump).await?;
+  let quality = options.quality.as_deref();
+  let mut attempts: Vec<(String, ResolveError)> = Vec::new();
+
+  for (player_index, player_url) in player_urls.iter().enumerate() {
+    let player_data =
+      match get_player_data(client, player_url, referer, &dump, player_index)
+        .await
+      {
+        Ok(PlayerSource::Data(p)) => p,
+        Ok(PlayerSource::Hls(hls_url)) => {
+          if let Err(e) = check_playlist(client, &hls_url).await {
+            attempts.push((
+              format!("player #{} script url", player_index),
+              ResolveError::from_error(e, ResolveStage::Playlist),
+            ));
+            continue;
+          }
+          info!("using player #{} script url", player_index);
+          return Ok(EpisodeStream {
+            hls_url,
+            player_url: player_url.clone(),
+            quality: None,
+            episode_url: url.clone(),
+            referer: referer.clone(),
+          });
+        }
+        Err(e) => {
+          warn!("player #{} failed: {}", player_index, e);
+          attempts.push((
+            format!("player #{} {}", player_index, player_url),
+            ResolveError::from_error(e, ResolveStage::PlayerPage),
+          ));
+          continue;
+        }
+      };
+
+    let qualities = player_data.qualities();
+    let candidates = match quality.map(|q| q.trim_end_matches('p')) {
+      Some(q) if qualities.iter().any(|a| a == q) => vec![q.to_string()],
+      Some(q) => {
+        attempts.push((
+          format!("player #{}", player_index),
+          ResolveError::new(
+            ResolveStage::PlayerData,
+            format!("quality {}p not offered ({})", q, qualities.join(", ")),
+          ),
+        ));
+        continue;
+      }
+      None => qualities,
+    };
+    if candidates.is_empty() {
+      attempts.push((
+        format!("player #{}", player_index),
+        ResolveError::new(
+          ResolveStage::PlayerData,
+          "no vid_url_* key in player data",
+        ),
+      ));
+      continue;
+    }
+
+    for quality in candidates {
+      let hls_urls = match get_quality_hls(
+        client,
+        player_url,
+        &player_data,
+        &quality,
+        &dump,
+        player_index,
+      )
+      .await
+      {
+        Ok(h) => h,
+        Err(e) => {
+          warn!("player #{} {}p failed: {}", player_index, quality, e);
+          attempts.push((
+            format!("player #{} {}p", player_index, quality),
+            ResolveError::from_error(e, ResolveStage::VideoData),
+          ));
+          continue;
+        }
+      };
+
+      for (entry, hls_url) in hls_urls.into_iter().enumerate() {
+        if let Err(e) = check_playlist(client, &hls_url).await {
+          warn!(
+            "player #{} {}p entry {} failed: {}",
+            player_index, quality, entry, e
+          );
+          attempts.push((
+            format!(
+              "player #{} {}p video_data[{}]",
+              player_index, quality, entry
+            ),
+            ResolveError::from_error(e, ResolveStage::Playlist),
+          ));
+          continue;
+        }
+
+        info!(
+          "using player #{} {}p video_data[{}]",
+          player_index, quality, entry
+        );
+        info!("hsl_url = {}...", &hls_url[..25]);
+        debug!("hsl_url = {}", hls_url);
+        return Ok(EpisodeStream {
+          hls_url,
+          player_url: player_url.clone(),
+          quality: Some(quality),
+          episode_url: url.clone(),
+          referer: referer.clone(),
+        });
+      }
+    }
   }
 
-  let mut count: usize = 0;
-  let mut segments = Vec::new();
+  // as far as any source got
+  let stage = attempts
+    .iter()
+    .map(|(_, e)| e.stage)
+    .max()
+    .unwrap_or(ResolveStage::PlayerPage);
+  let error = ResolveError {
+    stage,
+    reason: "no working source, tried:".to_string(),
+    attempts,
+  };
+  dump.save("error.txt", error.to_string().as_bytes());
+  Err(error.into())
+}
 
-  for task in tasks {
-    let segment = task.await?.unwrap();
-    count += 1;
-    print_progress(&filename, count, segment_urls.len());
-    segments.push(segment);
+// Every HLS url listed in the `video_data` response for `quality`
+async fn get_quality_hls(
+  client: &Client,
+  player_url: &str,
+  player_data: &PlayerData,
+  quality: &str,
+  dump: &DebugDump,
+  player_index: usize,
+) -> AsyncResult<Vec<String>> {
+  let video_url = match player_data.video_url(quality) {
+    Some(url) => format!("https://{}", url),
+    None => {
+      return Err(ResolveError::boxed(
+        ResolveStage::PlayerData,
+        format!("key vid_url_{} missing", quality),
+      ))
+    }
+  };
+
+  let req = client.get(video_url).header("Referer", player_url);
+  let res = http::send(client, req)
+    .await
+    .map_err(|e| ResolveError::boxed(ResolveStage::VideoData, e.to_string()))?;
+  let video_data = http::read_body(res).await?;
+  dump.save(
+    &format!("video_data-{}-{}.json", player_index, quality),
+    &video_data,
+  );
+  let video_data: Value = serde_json::from_slice(&video_data).map_err(|e| {
+    ResolveError::boxed(ResolveStage::VideoData, format!("json: {}", e))
+  })?;
+
+  let hls_urls: Vec<String> = match &video_data {
+    Value::Array(entries) => entries
+      .iter()
+      .filter_map(|entry| entry["url"].as_str())
+      .map(|url| url.to_string())
+      .collect(),
+    _ => vec![],
+  };
+
+  if hls_urls.is_empty() {
+    return Err(ResolveError::boxed(
+      ResolveStage::VideoData,
+      "key [*].url missing",
+    ));
   }
+  Ok(hls_urls)
+}
+
+// A source only counts as working if it actually serves a playlist
+async fn check_playlist(client: &Client, url: &str) -> AsyncResult<()> {
+  let content = fetch_playlist(client, url)
+    .await
+    .map_err(|e| ResolveError::boxed(ResolveStage::Playlist, e.to_string()))?;
+  if !content.trim_start().starts_with("#EXTM3U") {
+    return Err(ResolveError::boxed(
+      ResolveStage::Playlist,
+      "response is not an m3u8 playlist",
+    ));
+  }
+  Ok(())
+}
+
+pub const MAX_REFRESHES: usize = 3;
+// between playlist polls before the target duration is known
+const RECORD_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Which playlist of an episode a download follows, so the same one can be
+// found again after re-resolving the episode
+#[derive(Clone, Serialize, Deserialize)]
+pub enum PlaylistTarget {
+  Media,
+  Variant,
+  // by LANGUAGE and NAME, several renditions can share a language
+  Rendition {
+    kind: RenditionType,
+    language: Option<String>,
+    name: String,
+  },
+}
+
+struct DownloadContext<'a> {
+  client: &'a Client,
+  stream: &'a EpisodeStream,
+  options: &'a DownloadOptions,
+  downloader: Box<dyn SegmentDownloader>,
+}
+
+pub struct DownloadOptions {
+  pub max_concurrent: usize,
+  // tune concurrency up to max_concurrent instead of using it as is
+  pub adaptive: bool,
+  // race a second request for slow segments at the end of a download
+  pub hedge: bool,
+  pub audio: Vec<String>,
+  pub subs: Vec<String>,
+  pub mux: bool,
+  pub record: bool,
+  pub record_timeout: Option<Duration>,
+  // (from, to) in seconds
+  pub clip: Option<(f64, f64)>,
+  pub trim: bool,
+  // keep <file>.segments.json so single segments can be repaired later
+  pub segment_map: bool,
+  // add every written file to SHA256SUMS in its directory
+  pub checksums: bool,
+  // hand segments to an aria2 daemon instead of fetching them here
+  pub aria2: Option<Aria2Options>,
+  pub resolve: ResolveOptions,
+}
+
+pub async fn fetch_playlist(client: &Client, url: &str) -> AsyncResult<String> {
+  let req = client.get(url).header("Referer", host());
+  let res = http::send(client, req).await?.error_for_status()?;
+  http::read_text(res).await
+}
+
+// segments of an episode whose size is asked for with HEAD
+const SIZE_SAMPLES: usize = 3;
+
+pub struct SizeEstimate {
+  pub bytes: u64,
+  pub duration: f64,
+  // how the size was found
+  pub source: &'static str,
+  pub extension: &'static str,
+}
+
+// Content-Length of a segment without downloading it
+async fn segment_size(client: &Client, url: &str) -> Option<u64> {
+  let req = client.head(url).header("Referer", host());
+  let res = http::send(client, req)
+    .await
+    .ok()?
+    .error_for_status()
+    .ok()?;
+  // reqwest reports an empty body for HEAD, read the header itself
+  res
+    .headers()
+    .get(header::CONTENT_LENGTH)?
+    .to_str()
+    .ok()?
+    .parse()
+    .ok()
+}
+
+// Guesses the size of the episode behind `stream` without downloading it:
+// exact for byte range playlists, otherwise the Content-Length of a few
+// segments scaled to the whole duration, otherwise the variant's BANDWIDTH.
+// Alternate renditions are not counted.
+pub async fn estimate_episode(
+  client: &Client,
+  stream: &EpisodeStream,
+  options: &DownloadOptions,
+) -> AsyncResult<SizeEstimate> {
+  let content = fetch_playlist(client, &stream.hls_url).await?;
+  let TargetPlaylist {
+    mut playlist,
+    bandwidth,
+    ..
+  } = media_playlist_for(
+    client,
+    &stream.hls_url,
+    &content,
+    &PlaylistTarget::Variant,
+  )
+  .await?;
+  if let Some((from, to)) = options.clip {
+    playlist.clip(from, to);
+  }
+  let duration = playlist.duration();
+  let extension = playlist.extension();
+  let estimate = |bytes: f64, source| SizeEstimate {
+    bytes: bytes as u64,
+    duration,
+    source,
+    extension,
+  };
 
+  let segments = &playlist.segments;
+  if segments.is_empty() {
+    return Err("playlist has no segments".into());
+  }
+  if segments.iter().all(|s| s.byte_range.is_some()) {
+    let bytes = segments
+      .iter()
+      .filter_map(|s| s.byte_range.as_ref())
+      .map(|r| r.length)
+      .sum::<u64>();
+    return Ok(estimate(bytes as f64, "byte ranges"));
+  }
+
+  // spread over the episode, intros and credits are often smaller
+  let (mut sampled_bytes, mut sampled_duration) = (0, 0.0);
+  let samples = SIZE_SAMPLES.min(segments.len());
+  for i in 0..samples {
+    let segment = &segments[(2 * i + 1) * segments.len() / (2 * samples)];
+    if let Some(bytes) = segment_size(client, &segment.url).await {
+      sampled_bytes += bytes;
+      sampled_duration += segment.duration;
+    }
+  }
+  if sampled_duration > 0.0 {
+    let bytes = sampled_bytes as f64 / sampled_duration * duration;
+    return Ok(estimate(bytes, "sampled"));
+  }
+
+  match bandwidth {
+    Some(bandwidth) => {
+      Ok(estimate(bandwidth as f64 / 8.0 * duration, "bandwidth"))
+    }
+    None => Err("no Content-Length or BANDWIDTH to estimate from".into()),
+  }
+}
+
+// Downloads the episode behind `stream`, which may be a media playlist or a
+// master playlist with alternate renditions, and returns the written path.
+pub async fn download_episode(
+  client: &Client,
+  stream: &EpisodeStream,
+  output: &str,
+  options: &DownloadOptions,
+) -> AsyncResult<String> {
+  let files = download_episode_files(client, stream, output, options).await?;
+  if options.checksums {
+    for file in &files {
+      checksum::record(Path::new(file))?;
+    }
+  }
+  Ok(files[0].clone())
+}
+
+// aria2 when configured, otherwise the built in downloader
+fn segment_downloader(
+  client: &Client,
+  options: &DownloadOptions,
+) -> Box<dyn SegmentDownloader> {
+  let http = HttpDownloader::new(
+    client,
+    options.max_concurrent,
+    options.adaptive,
+    options.hedge,
+  );
+  match &options.aria2 {
+    Some(aria2) => Box::new(Aria2Downloader::new(aria2, http)),
+    None => Box::new(http),
+  }
+}
+
+// The episode first, then renditions that were not muxed into it
+async fn download_episode_files(
+  client: &Client,
+  stream: &EpisodeStream,
+  output: &str,
+  options: &DownloadOptions,
+) -> AsyncResult<Vec<String>> {
+  let url = &stream.hls_url;
+  let ctx = DownloadContext {
+    client,
+    stream,
+    options,
+    downloader: segment_downloader(client, options),
+  };
+  let content = fetch_playlist(client, url).await?;
+
+  if options.record {
+    let media =
+      media_playlist_for(client, url, &content, &PlaylistTarget::Variant)
+        .await?;
+    let filename = record_playlist(client, &media.url, output, options).await?;
+    return Ok(vec![filename]);
+  }
+
+  if !hls::is_master_playlist(&content) {
+    if !options.audio.is_empty() || !options.subs.is_empty() {
+      warn!("no alternate renditions in a media playlist");
+    }
+    let playlist = hls::parse_media_playlist(&content, url);
+    let filename = download_playlist(
+      &ctx,
+      playlist,
+      PlaylistTarget::Media,
+      "./segments",
+      output,
+    )
+    .await?;
+    fs::remove_dir_all("./segments")?;
+    return Ok(vec![filename]);
+  }
+
+  let master = hls::parse_master_playlist(&content, url);
+  let Some(variant) = master.best_variant() else {
+    return Err("master playlist has no variants".into());
+  };
   info!(
-    "successful segments {} / {}",
-    segments.len(),
-    segment_urls.len()
+    "variant {} ({} bps)",
+    variant.resolution.as_deref().unwrap_or("unknown"),
+    variant.bandwidth
   );
 
-  fs::remove_file("./segments/all.ts").unwrap_or({
-    warn!("all.ts does not exist (this is expected)");
+  let audio = master.renditions_for(variant, RenditionType::Audio);
+  let subs = master.renditions_for(variant, RenditionType::Subtitles);
+  audio.iter().chain(subs.iter()).for_each(|r| {
+    info!(
+      "rendition {} {} ({}){}",
+      if r.kind == RenditionType::Audio {
+        "audio"
+      } else {
+        "subs"
+      },
+      r.label(),
+      r.name,
+      if r.default { " default" } else { "" }
+    );
   });
 
-  let mut all_ts = fs::OpenOptions::new()
-    .create_new(true)
-    .append(true)
-    .open("./segments/all.ts")
-    .unwrap();
-
-  info!("Combining...");
-  segments.sort_by_key(|a| a.index);
-  segments.iter().for_each(|segment| {
-    let mut segment_ts = fs::OpenOptions::new()
-      .read(true)
-      .open(&segment.filename)
-      .unwrap();
-    io::copy(&mut segment_ts, &mut all_ts).unwrap();
+  // a variant whose whole audio group lives in separate playlists has no
+  // sound of its own, take the group's default without --audio
+  let audio_separate = variant.audio.as_ref().is_some_and(|group| {
+    master
+      .renditions
+      .iter()
+      .filter(|r| r.kind == RenditionType::Audio && &r.group_id == group)
+      .all(|r| r.url.is_some())
   });
+  let default_audio = if options.audio.is_empty() && audio_separate {
+    let default = audio.iter().find(|r| r.default).or(audio.first()).copied();
+    if let Some(rendition) = default {
+      info!(
+        "variant has no audio of its own, adding {}",
+        rendition.label()
+      );
+    }
+    default
+  } else {
+    None
+  };
 
-  if let Err(e) = fs::rename("./segments/all.ts", filename) {
-    debug!("{}", e);
+  let selected: Vec<&Rendition> = audio
+    .into_iter()
+    .filter(|r| {
+      options.audio.iter().any(|wanted| r.matches(wanted))
+        || default_audio.is_some_and(|d| std::ptr::eq(d, *r))
+    })
+    .chain(
+      subs
+        .into_iter()
+        .filter(|r| options.subs.iter().any(|wanted| r.matches(wanted))),
+    )
+    .collect();
+
+  let mut jobs = vec![(
+    variant.url.clone(),
+    "video".to_string(),
+    output.to_string(),
+    PlaylistTarget::Variant,
+  )];
+  let mut staged = BTreeSet::new();
+  for rendition in &selected {
+    let kind = match rendition.kind {
+      RenditionType::Audio => "audio",
+      _ => "subs",
+    };
+    // the language alone when it is unique, otherwise with the name too
+    let mut label = rendition.label().sanitize();
+    if staged.contains(&format!("{}-{}", kind, label)) {
+      label = format!("{}.{}", label, rendition.name.sanitize());
+    }
+    let base = label.clone();
+    let mut n = 1;
+    while !staged.insert(format!("{}-{}", kind, label)) {
+      n += 1;
+      label = format!("{}-{}", base, n);
+    }
+    jobs.push((
+      rendition.url.clone().unwrap_or_default(),
+      format!("{}-{}", kind, label),
+      format!("{}.{}", output, label),
+      PlaylistTarget::Rendition {
+        kind: rendition.kind,
+        language: rendition.language.clone(),
+        name: rendition.name.clone(),
+      },
+    ));
   }
 
+  let ctx = &ctx;
+  let downloads =
+    jobs
+      .into_iter()
+      .map(|(url, name, output, target)| async move {
+        let content = fetch_playlist(client, &url).await?;
+        let playlist = hls::parse_media_playlist(&content, &url);
+        let staging = format!("./segments/{}", name);
+        download_playlist(ctx, playlist, target, &staging, &output).await
+      });
+  let mut files = futures::future::try_join_all(downloads).await?;
   fs::remove_dir_all("./segments")?;
 
+  if !options.mux || files.len() == 1 {
+    return Ok(files);
+  }
+  let video_file = files.remove(0);
+
+  let tracks: Vec<(String, String, RenditionType)> = files
+    .into_iter()
+    .zip(selected.iter())
+    .map(|(file, rendition)| (file, rendition.label(), rendition.kind))
+    .collect();
+  let muxed = format!("{}.mkv", output);
+  match video::mux(&video_file, &tracks, &muxed) {
+    Ok(()) => {
+      remove_output(&video_file)?;
+      for (file, _, _) in &tracks {
+        remove_output(file)?;
+      }
+      Ok(vec![muxed])
+    }
+    Err(e) => {
+      warn!("muxing failed, keeping sidecar files: {}", e);
+      let files = tracks.into_iter().map(|(file, _, _)| file);
+      Ok(std::iter::once(video_file).chain(files).collect())
+    }
+  }
+}
+
+// Removes a file that was muxed away, with its verify report and segment map
+fn remove_output(file: &str) -> io::Result<()> {
+  fs::remove_file(file)?;
+  let path = Path::new(file);
+  for sidecar in [verify::Report::path_for(path), SegmentMap::path_for(path)] {
+    if sidecar.exists() {
+      fs::remove_file(sidecar)?;
+    }
+  }
   Ok(())
 }
 
-async fn download_segment(
-  index: i32,
-  url: String,
-  semaphore: &Semaphore,
-) -> Option<Segment> {
-  let _permit = semaphore.acquire().await.unwrap();
+// Re-runs the episode resolution chain and returns a fresh copy of the
+// playlist `target` points at, clipped the same way as the original
+async fn refresh_playlist(
+  ctx: &DownloadContext<'_>,
+  target: &PlaylistTarget,
+) -> AsyncResult<MediaPlaylist> {
+  let resolve = ctx.options.resolve.pinned(&ctx.stream.quality);
+  resolve_playlist(
+    ctx.client,
+    &ctx.stream.episode_url,
+    &ctx.stream.referer,
+    &resolve,
+    target,
+    ctx.options.clip,
+  )
+  .await
+}
 
-  let client = reqwest::Client::new();
-  let res = client
-    .get(url)
-    .header("User-Agent", USER_AGENT)
-    .header("Referer", HOST)
-    .header("Origin", HOST)
-    .send()
-    .await;
+// Resolves the episode and returns the media playlist `target` points at,
+// clipped to `clip` seconds
+pub async fn resolve_playlist(
+  client: &Client,
+  episode_url: &String,
+  referer: &String,
+  resolve: &ResolveOptions,
+  target: &PlaylistTarget,
+  clip: Option<(f64, f64)>,
+) -> AsyncResult<MediaPlaylist> {
+  let stream = get_episode_hls(client, episode_url, referer, resolve).await?;
+  let content = fetch_playlist(client, &stream.hls_url).await?;
+  let TargetPlaylist { mut playlist, .. } =
+    media_playlist_for(client, &stream.hls_url, &content, target).await?;
+  if let Some((from, to)) = clip {
+    playlist.clip(from, to);
+  }
+  Ok(playlist)
+}
 
-  if res.is_err() {
-    return None;
+// A media playlist and where it came from
+pub struct TargetPlaylist {
+  pub url: String,
+  pub playlist: MediaPlaylist,
+  // BANDWIDTH of the variant, when it has one
+  pub bandwidth: Option<u64>,
+}
+
+// Finds the media playlist `target` points at from the playlist at `url`
+// with `content`. A media playlist is its own variant.
+pub async fn media_playlist_for(
+  client: &Client,
+  url: &str,
+  content: &str,
+  target: &PlaylistTarget,
+) -> AsyncResult<TargetPlaylist> {
+  let media = |url: &str, content: &str| TargetPlaylist {
+    url: url.to_string(),
+    playlist: hls::parse_media_playlist(content, url),
+    bandwidth: None,
+  };
+  if matches!(target, PlaylistTarget::Media)
+    || !hls::is_master_playlist(content)
+  {
+    if let PlaylistTarget::Rendition { .. } = target {
+      return Err("rendition missing from a media playlist".into());
+    }
+    return Ok(media(url, content));
   }
 
-  let bytes = res.unwrap().bytes().await.unwrap();
+  let master = hls::parse_master_playlist(content, url);
+  let (url, bandwidth) = match target {
+    PlaylistTarget::Rendition {
+      kind,
+      language,
+      name,
+    } => {
+      let url = master
+        .renditions
+        .iter()
+        .find(|r| r.kind == *kind && r.language == *language && r.name == *name)
+        .and_then(|r| r.url.clone());
+      let Some(url) = url else {
+        return Err(
+          format!("rendition {} missing from the playlist", name).into(),
+        );
+      };
+      (url, None)
+    }
+    _ => {
+      let Some(variant) = master.best_variant() else {
+        return Err("master playlist has no variants".into());
+      };
+      (
+        variant.url.clone(),
+        Some(variant.bandwidth).filter(|&b| b > 0),
+      )
+    }
+  };
+  let content = fetch_playlist(client, &url).await?;
+  Ok(TargetPlaylist {
+    bandwidth,
+    ..media(&url, &content)
+  })
+}
 
-  let filename = format!("./segments/seg{:04}.ts", index);
+// Follows a live playlist, appending new segments to the output in order
+// until `#EXT-X-ENDLIST`, the record timeout or Ctrl-C.
+async fn record_playlist(
+  client: &Client,
+  url: &str,
+  output: &str,
+  options: &DownloadOptions,
+) -> AsyncResult<String> {
+  let started = Instant::now();
+  let ctrl_c = tokio::signal::ctrl_c();
+  tokio::pin!(ctrl_c);
 
-  let mut file = File::create(&filename).unwrap();
-  let encrypted = bytes.to_vec();
-  file.write_all(&encrypted).unwrap();
+  let mut out: Option<(String, File)> = None;
+  let mut next_sequence: Option<u64> = None;
+  let mut count: usize = 0;
+  // time until the next poll, none before the first
+  let mut interval: Option<Duration> = None;
+
+  'poll: loop {
+    if let Some(interval) = interval {
+      if let Some(timeout) = options.record_timeout {
+        if started.elapsed() >= timeout {
+          info!("record timeout reached");
+          break;
+        }
+      }
+      tokio::select! {
+        _ = tokio::time::sleep(interval) => {}
+        _ = &mut ctrl_c => {
+          info!("interrupted");
+          break;
+        }
+      }
+    }
+    // failures below keep polling at the last known pace
+    interval = interval.or(Some(RECORD_RETRY_INTERVAL));
+
+    let content = match fetch_playlist(client, url).await {
+      Ok(content) => content,
+      Err(e) => {
+        warn!("playlist poll failed: {}, polling again", e);
+        continue;
+      }
+    };
+    let playlist = hls::parse_media_playlist(&content, url);
+
+    let (filename, file) = match &mut out {
+      Some(out) => out,
+      None => {
+        let filename = format!("{}.{}", output, playlist.extension());
+        let init = match &playlist.init {
+          Some(init) => {
+            info!("fetching init section");
+            let fetched =
+              fetch_segment_bytes(client, &init.url, init.byte_range.as_ref())
+                .await;
+            match fetched {
+              Ok(bytes) => Some(bytes),
+              Err(e) => {
+                warn!("init section failed: {}, polling again", e);
+                continue;
+              }
+            }
+          }
+          None => None,
+        };
+        let mut file = File::create(&filename)?;
+        if let Some(init) = init {
+          file.write_all(&init)?;
+        }
+        info!("Recording to {}", filename);
+        out.insert((filename, file))
+      }
+    };
+
+    let first = playlist.media_sequence;
+    let skip = match next_sequence {
+      Some(next) if next < first => {
+        warn!("missed {} segments, playlist moved too fast", first - next);
+        0
+      }
+      Some(next) => (next - first) as usize,
+      None => 0,
+    };
+
+    for (offset, segment) in playlist.segments.iter().enumerate().skip(skip) {
+      if (&mut ctrl_c).now_or_never().is_some() {
+        info!("interrupted");
+        break 'poll;
+      }
+
+      let sequence = first + offset as u64;
+      let bytes = match downloader::fetch_segment_retrying(
+        client,
+        sequence as usize,
+        segment,
+      )
+      .await
+      {
+        Ok(bytes) => bytes,
+        // picked up again on the next poll while still listed
+        Err(e) => {
+          warn!("segment {} failed: {}, polling again", sequence, e);
+          break;
+        }
+      };
+      file.write_all(&bytes)?;
+      next_sequence = Some(sequence + 1);
+      count += 1;
+      print_progress(filename, count, count);
+    }
+    next_sequence = next_sequence.or(Some(first));
+
+    if playlist.ended
+      && next_sequence >= Some(first + playlist.segments.len() as u64)
+    {
+      info!("playlist ended");
+      break;
+    }
+    interval = Some(Duration::from_secs_f64(playlist.target_duration.max(1.0)));
+  }
+
+  match out {
+    Some((filename, _)) => {
+      info!("recorded {} segments", count);
+      Ok(filename)
+    }
+    None => Err("nothing recorded".into()),
+  }
+}
+
+// Downloads every segment of `playlist` into `staging` and combines them
+// into `{output}.{extension}`, returning the path that was written.
+async fn download_playlist(
+  ctx: &DownloadContext<'_>,
+  mut playlist: MediaPlaylist,
+  target: PlaylistTarget,
+  staging: &str,
+  output: &str,
+) -> AsyncResult<String> {
+  fs::create_dir_all(staging)?;
+
+  let options = ctx.options;
+  let original_total = playlist.segments.len();
+  let clip_start = match options.clip {
+    Some((from, to)) => {
+      let Some((first, start)) = playlist.clip(from, to) else {
+        return Err("clip range is outside of the episode".into());
+      };
+      info!(
+        "clip {:.1}s-{:.1}s uses segments {}..={} of {}",
+        from,
+        to,
+        first,
+        first + playlist.segments.len() - 1,
+        original_total
+      );
+      Some(from - start)
+    }
+    None => None,
+  };
+  if !playlist.ended {
+    warn!("playlist has no #EXT-X-ENDLIST, use --record to follow it");
+  }
+
+  let extension = playlist.extension();
+  let filename = format!("{}.{}", output, extension);
+
+  let init_filename = match &playlist.init {
+    Some(init) => {
+      info!("fetching init section");
+      let bytes =
+        fetch_segment_bytes(ctx.client, &init.url, init.byte_range.as_ref())
+          .await?;
+      let init_filename = format!("{}/init.{}", staging, extension);
+      File::create(&init_filename)?.write_all(&bytes)?;
+      Some(init_filename)
+    }
+    None => None,
+  };
+
+  let total = playlist.segments.len();
+  let mut pending: Vec<usize> = (0..total).collect();
+  let mut segments = Vec::new();
+  let mut refreshes = 0;
+
+  loop {
+    let jobs = pending
+      .iter()
+      .map(|&idx| SegmentJob {
+        index: idx,
+        url: playlist.segments[idx].url.clone(),
+        byte_range: playlist.segments[idx].byte_range.clone(),
+        filename: format!("{}/seg{:04}.{}", staging, idx, extension),
+      })
+      .collect();
+
+    let mut results = ctx.downloader.download(jobs);
+    let mut failures = Vec::new();
+    while let Some(result) = results.next().await {
+      match result {
+        Ok(segment) => {
+          segments.push(segment);
+          print_progress(&filename, segments.len(), total);
+        }
+        Err(failure) => failures.push(failure),
+      }
+    }
+
+    if failures.is_empty() {
+      break;
+    }
+
+    let unauthorized = failures.iter().filter(|f| f.unauthorized).count();
+    if unauthorized == 0 || refreshes == MAX_REFRESHES {
+      return Err(
+        format!("{} of {} segments failed", failures.len(), total).into(),
+      );
+    }
+
+    refreshes += 1;
+    warn!(
+      "{} segments unauthorized, re-resolving episode ({}/{})",
+      unauthorized, refreshes, MAX_REFRESHES
+    );
+    let fresh = refresh_playlist(ctx, &target).await?;
+    pending = failures.iter().map(|f| f.index).collect();
+    for &idx in &pending {
+      let durations = playlist.segments.iter().map(|s| s.duration);
+      let fresh_index = fresh.map_index(durations, idx);
+      let Some(fresh_segment) = fresh_index.and_then(|i| fresh.segments.get(i))
+      else {
+        return Err(
+          format!("segment {} missing from refreshed playlist", idx).into(),
+        );
+      };
+      playlist.segments[idx].url = fresh_segment.url.clone();
+      playlist.segments[idx].byte_range = fresh_segment.byte_range.clone();
+    }
+  }
+
+  info!("successful segments {} / {}", segments.len(), total);
+  ctx.downloader.log_summary();
+
+  let all_filename = format!("{}/all", staging);
+  let mut all = fs::OpenOptions::new()
+    .create(true)
+    .truncate(true)
+    .write(true)
+    .open(&all_filename)
+    .unwrap();
+
+  info!("Combining...");
+  let mut init_length = 0;
+  if let Some(init_filename) = &init_filename {
+    let mut init = fs::OpenOptions::new().read(true).open(init_filename)?;
+    init_length = io::copy(&mut init, &mut all)?;
+  }
+
+  segments.sort_by_key(|a| a.index);
+  let mut mapped = Vec::new();
+  if extension == "vtt" {
+    let files: Vec<&str> =
+      segments.iter().map(|s| s.filename.as_str()).collect();
+    video::concat_vtt(&files, &mut all)?;
+  } else {
+    let mut offset = init_length;
+    for segment in &segments {
+      let bytes = fs::read(&segment.filename)?;
+      all.write_all(&bytes)?;
+      if options.segment_map {
+        let source = &playlist.segments[segment.index];
+        mapped.push(MappedSegment {
+          index: segment.index,
+          offset,
+          length: bytes.len() as u64,
+          duration: source.duration,
+          url: source.url.clone(),
+          sha256: repair::sha256(&bytes),
+        });
+      }
+      offset += bytes.len() as u64;
+    }
+  }
 
-  Some(Segment { index, filename })
+  if let Err(e) = fs::rename(&all_filename, &filename) {
+    debug!("{}", e);
+  }
+
+  let mut trimmed_duration = None;
+  if let (Some(offset), Some((from, to)), true) =
+    (clip_start, options.clip, options.trim)
+  {
+    if extension != "vtt" {
+      let trimmed = format!("{}/trimmed.{}", staging, extension);
+      let duration = (to - from).min(playlist.duration() - offset);
+      video::trim(&filename, offset, duration, &trimmed)?;
+      fs::rename(&trimmed, &filename)?;
+      trimmed_duration = Some(duration);
+    }
+  }
+
+  if options.segment_map && extension != "vtt" {
+    if trimmed_duration.is_some() {
+      warn!("no segment map for a trimmed clip, it was re-cut");
+    } else {
+      let map = SegmentMap {
+        episode_url: ctx.stream.episode_url.clone(),
+        referer: ctx.stream.referer.clone(),
+        quality: ctx.stream.quality.clone(),
+        target,
+        clip: options.clip,
+        init_length,
+        segments: mapped,
+      };
+      map.save(Path::new(&filename))?;
+    }
+  }
+
+  if extension == "ts" {
+    verify_output(&filename, &playlist, trimmed_duration);
+  }
+
+  Ok(filename)
 }
 
-fn parse_hls(content: String) -> Vec<String> {
-  let lines: Vec<String> = content.lines().map(|l| l.to_string()).collect();
-  let n = lines.len();
-  let mut index = 0;
-  let mut segment_urls: Vec<String> = Vec::new();
+// Checks the combined file against the playlist and keeps the report next
+// to it. Problems are only warned about, the file is still usable.
+fn verify_output(
+  filename: &str,
+  playlist: &MediaPlaylist,
+  trimmed_duration: Option<f64>,
+) {
+  let expected = trimmed_duration.unwrap_or_else(|| playlist.duration());
+  let report = match verify::verify_ts(Path::new(filename), Some(expected)) {
+    Ok(r) => r,
+    Err(e) => {
+      warn!("unable to verify {}: {}", filename, e);
+      return;
+    }
+  };
 
-  while index < n {
-    let line = &lines[index];
-    if line.starts_with(HLS_SEG_TAG) {
-      index += 1;
-      let segment_url = &lines[index];
-      segment_urls.push(segment_url.clone());
+  if report.is_ok() {
+    info!("verified {:.1}s of {:.1}s", report.duration, expected);
+  } else {
+    report
+      .problems
+      .iter()
+      .for_each(|problem| warn!("{}: {}", filename, problem));
+    // timestamps of a trimmed file no longer line up with the segments
+    let suspect_segments: BTreeSet<usize> = report
+      .gaps
+      .iter()
+      .filter_map(|gap| playlist.segment_at(gap.at))
+      .collect();
+    if trimmed_duration.is_none() && !suspect_segments.is_empty() {
+      warn!("timestamp gaps around segments {:?}", suspect_segments);
     }
+  }
+  if let Err(e) = report.save(Path::new(filename)) {
+    warn!("unable to save verify report: {}", e);
+  }
+}
+
+// What a player or downloader has to send to get the playlist and segments
+pub fn stream_headers() -> Vec<(String, String)> {
+  let mut headers = vec![
+    ("Referer".to_string(), host()),
+    ("Origin".to_string(), host()),
+  ];
+  headers.extend(http::sent_headers());
+  headers
+}
 
-    index += 1;
+// Segments go through the same client as the pages so the user agent and
+// cookies match
+pub async fn fetch_segment_bytes(
+  client: &Client,
+  url: &str,
+  byte_range: Option<&ByteRange>,
+) -> AsyncResult<Vec<u8>> {
+  let mut req = client
+    .get(url)
+    .header("Referer", host())
+    .header("Origin", host());
+  if let Some(range) = byte_range {
+    req = req.header("Range", range.header_value());
+  }
+
+  let res = http::send_segment(client, req).await?.error_for_status()?;
+  http::read_segment(res).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn player_data(html: &str) -> PlayerData {
+    match parse_player_page(html, |_| {}).unwrap() {
+      PlayerSource::Data(data) => data,
+      PlayerSource::Hls(url) => panic!("expected _aldata, got {}", url),
+    }
+  }
+
+  fn assert_fixture_data(data: &PlayerData) {
+    assert_eq!(data.qualities(), vec!["1080", "720"]);
+    assert_eq!(data.video_url("720"), Some("video.example/v/720"));
   }
 
-  segment_urls
+  #[test]
+  fn plain_aldata() {
+    let data = player_data(include_str!("../tests/fixtures/player-plain.html"));
+    assert_fixture_data(&data);
+  }
+
+  #[cfg(feature = "js")]
+  #[test]
+  fn eval_packed_aldata() {
+    let data =
+      player_data(include_str!("../tests/fixtures/player-packed.html"));
+    assert_fixture_data(&data);
+  }
+
+  #[cfg(feature = "js")]
+  #[test]
+  fn player_setup_url() {
+    let html = include_str!("../tests/fixtures/player-setup.html");
+    match parse_player_page(html, |_| {}).unwrap() {
+      PlayerSource::Hls(url) => {
+        assert_eq!(url, "https://video.example/hls/ep-1/index.m3u8")
+      }
+      PlayerSource::Data(_) => panic!("expected a playlist url"),
+    }
+  }
+
+  #[cfg(feature = "js")]
+  #[test]
+  fn scripts_see_the_configured_user_agent() {
+    let html = r#"<script>
+      if (navigator.userAgent === 'agent "quoted"/1.0') {
+        jwplayer('p').setup({ file: 'https://video.example/ua.m3u8' });
+      }
+    </script>"#;
+    match js::evaluate_player_page(
+      html,
+      ALDATA_PATTERN,
+      r#"agent "quoted"/1.0"#,
+    ) {
+      Ok(PlayerScriptResult::VideoUrl(url)) => {
+        assert_eq!(url, "https://video.example/ua.m3u8")
+      }
+      _ => panic!("user agent did not reach the scripts"),
+    }
+  }
 }
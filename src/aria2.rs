@@ -0,0 +1,478 @@
+use std::{collections::VecDeque, fs, path::Path, time::Duration};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use log::{debug, info, warn};
+use reqwest::Client;
+use serde_json::{json, Value};
+
+use crate::{
+  api,
+  downloader::{
+    HttpDownloader, Segment, SegmentDownloader, SegmentFailure, SegmentJob,
+  },
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// polls in a row the daemon may miss before its downloads count as failed
+const MAX_POLL_ERRORS: usize = 10;
+// aria2 retries on its own
+const MAX_TRIES: &str = "4";
+// aria2's error code for a failed HTTP authorization
+const AUTH_FAILED: &str = "24";
+
+#[derive(Clone)]
+pub struct Aria2Options {
+  // e.g. http://localhost:6800/jsonrpc
+  pub url: String,
+  // --rpc-secret of the daemon
+  pub secret: Option<String>,
+}
+
+// Submits segments to an aria2 daemon over JSON-RPC and polls them until
+// they are done. The daemon has to see the same filesystem. Byte ranges go
+// through `fallback`, aria2 can not fetch part of a file.
+pub struct Aria2Downloader {
+  rpc: Client,
+  options: Aria2Options,
+  headers: Vec<String>,
+  fallback: HttpDownloader,
+}
+
+struct Submitted {
+  gid: String,
+  index: usize,
+  filename: String,
+}
+
+// What one download_pass stream is working through
+struct PassState {
+  unsubmitted: Option<Vec<SegmentJob>>,
+  submitted: Vec<Submitted>,
+  ready: VecDeque<Result<Segment, SegmentFailure>>,
+  poll_errors: usize,
+}
+
+impl Aria2Downloader {
+  pub fn new(
+    options: &Aria2Options,
+    fallback: HttpDownloader,
+  ) -> Aria2Downloader {
+    info!("handing segments to aria2 at {}", options.url);
+    Aria2Downloader {
+      rpc: Client::new(),
+      options: options.clone(),
+      headers: api::stream_headers()
+        .into_iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect(),
+      fallback,
+    }
+  }
+
+  fn token(&self) -> Option<Value> {
+    self
+      .options
+      .secret
+      .as_ref()
+      .map(|s| json!(format!("token:{}", s)))
+  }
+
+  // Errors are strings, these are held across awaits of a Send stream
+  async fn call(
+    &self,
+    method: &str,
+    params: Vec<Value>,
+  ) -> Result<Value, String> {
+    let body = json!({
+      "jsonrpc": "2.0",
+      "id": "anilife-dl",
+      "method": method,
+      "params": params,
+    });
+    let res = self
+      .rpc
+      .post(&self.options.url)
+      .json(&body)
+      .send()
+      .await
+      .map_err(|e| e.to_string())?;
+    let reply: Value = res.json().await.map_err(|e| e.to_string())?;
+    if let Some(error) = reply.get("error") {
+      let message = error["message"].as_str().unwrap_or("unknown error");
+      return Err(format!("{}: {}", method, message));
+    }
+    Ok(reply["result"].clone())
+  }
+
+  // the secret goes first in every call but system.multicall itself
+  fn params(&self, params: Vec<Value>) -> Vec<Value> {
+    self.token().into_iter().chain(params).collect()
+  }
+
+  async fn submit(&self, job: &SegmentJob) -> Result<String, String> {
+    let path = Path::new(&job.filename);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    // the daemon has its own working directory
+    let dir = fs::canonicalize(dir).map_err(|e| e.to_string())?;
+    let out = path.file_name().unwrap_or_default().to_string_lossy();
+
+    let options = json!({
+      "dir": dir.to_string_lossy(),
+      "out": out,
+      "header": self.headers,
+      "allow-overwrite": "true",
+      "auto-file-renaming": "false",
+      "max-tries": MAX_TRIES,
+    });
+    let gid = self
+      .call("aria2.addUri", self.params(vec![json!([job.url]), options]))
+      .await?;
+    gid
+      .as_str()
+      .map(|g| g.to_string())
+      .ok_or("no gid".to_string())
+  }
+
+  // status, errorCode and errorMessage of every gid in one round trip, or
+  // the fault for a gid the daemon no longer knows
+  async fn statuses(
+    &self,
+    gids: &[&str],
+  ) -> Result<Vec<Result<Value, String>>, String> {
+    let calls: Vec<Value> = gids
+      .iter()
+      .map(|gid| {
+        json!({
+          "methodName": "aria2.tellStatus",
+          "params": self.params(vec![
+            json!(gid),
+            json!(["status", "errorCode", "errorMessage"]),
+          ]),
+        })
+      })
+      .collect();
+    let result = self.call("system.multicall", vec![json!(calls)]).await?;
+    // every successful entry is wrapped in a one element array, faults are
+    // bare {code, message} objects
+    Ok(
+      result
+        .as_array()
+        .ok_or("multicall did not return a list")?
+        .iter()
+        .map(|entry| match entry.get(0) {
+          Some(status) => Ok(status.clone()),
+          None => Err(
+            entry["message"]
+              .as_str()
+              .unwrap_or("unknown fault")
+              .to_string(),
+          ),
+        })
+        .collect(),
+    )
+  }
+
+  // Leaves finished downloads out of the daemon's list
+  async fn forget(&self, gid: &str) {
+    let params = self.params(vec![json!(gid)]);
+    if let Err(e) = self.call("aria2.removeDownloadResult", params).await {
+      debug!("unable to remove aria2 result {}: {}", gid, e);
+    }
+  }
+
+  async fn poll(&self, state: &mut PassState) {
+    let gids: Vec<&str> =
+      state.submitted.iter().map(|s| s.gid.as_str()).collect();
+    let statuses = match self.statuses(&gids).await {
+      Ok(statuses) => statuses,
+      Err(e) => {
+        state.poll_errors += 1;
+        warn!("aria2 status failed: {}", e);
+        if state.poll_errors >= MAX_POLL_ERRORS {
+          for submitted in state.submitted.drain(..) {
+            state.ready.push_back(Err(SegmentFailure {
+              index: submitted.index,
+              unauthorized: false,
+            }));
+          }
+        }
+        return;
+      }
+    };
+    state.poll_errors = 0;
+
+    let mut still_running = Vec::new();
+    for (submitted, status) in state.submitted.drain(..).zip(statuses) {
+      // the daemon restarted or purged the result
+      let status = match status {
+        Ok(status) => status,
+        Err(fault) => {
+          warn!("segment {} lost in aria2: {}", submitted.index, fault);
+          self.forget(&submitted.gid).await;
+          state.ready.push_back(Err(SegmentFailure {
+            index: submitted.index,
+            unauthorized: false,
+          }));
+          continue;
+        }
+      };
+      let result = match status["status"].as_str() {
+        Some("complete") => Ok(Segment {
+          index: submitted.index,
+          filename: submitted.filename,
+        }),
+        Some("error") | Some("removed") => {
+          let code = status["errorCode"].as_str().unwrap_or("");
+          let message = status["errorMessage"].as_str().unwrap_or("removed");
+          warn!("segment {} failed in aria2: {}", submitted.index, message);
+          Err(SegmentFailure {
+            index: submitted.index,
+            unauthorized: code == AUTH_FAILED
+              || message.contains("status=401")
+              || message.contains("status=403"),
+          })
+        }
+        // active, waiting or paused
+        _ => {
+          still_running.push(submitted);
+          continue;
+        }
+      };
+      self.forget(&submitted.gid).await;
+      state.ready.push_back(result);
+    }
+    state.submitted = still_running;
+  }
+
+  fn download_pass(
+    &self,
+    jobs: Vec<SegmentJob>,
+  ) -> BoxStream<'_, Result<Segment, SegmentFailure>> {
+    let state = PassState {
+      unsubmitted: Some(jobs),
+      submitted: Vec::new(),
+      ready: VecDeque::new(),
+      poll_errors: 0,
+    };
+
+    stream::unfold(state, move |mut state| async move {
+      loop {
+        if let Some(result) = state.ready.pop_front() {
+          return Some((result, state));
+        }
+
+        if let Some(jobs) = state.unsubmitted.take() {
+          for job in jobs {
+            match self.submit(&job).await {
+              Ok(gid) => state.submitted.push(Submitted {
+                gid,
+                index: job.index,
+                filename: job.filename,
+              }),
+              Err(e) => {
+                warn!("unable to queue segment {} in aria2: {}", job.index, e);
+                state.ready.push_back(Err(SegmentFailure {
+                  index: job.index,
+                  unauthorized: false,
+                }));
+              }
+            }
+          }
+          continue;
+        }
+
+        if state.submitted.is_empty() {
+          return None;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+        self.poll(&mut state).await;
+      }
+    })
+    .boxed()
+  }
+}
+
+impl SegmentDownloader for Aria2Downloader {
+  fn download(
+    &self,
+    jobs: Vec<SegmentJob>,
+  ) -> BoxStream<'_, Result<Segment, SegmentFailure>> {
+    let (ranged, whole): (Vec<SegmentJob>, Vec<SegmentJob>) =
+      jobs.into_iter().partition(|job| job.byte_range.is_some());
+    if ranged.is_empty() {
+      return self.download_pass(whole);
+    }
+    stream::select(self.download_pass(whole), self.fallback.download(ranged))
+      .boxed()
+  }
+
+  fn log_summary(&self) {
+    self.fallback.log_summary();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    env,
+    sync::{Arc, Mutex},
+  };
+
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+  };
+
+  use super::*;
+
+  const SECRET: &str = "s3cret";
+
+  // What aria2 answers for segments seg0000 (completes), seg0001 (fails
+  // authorization) and seg0002 (forgotten by a restarted daemon)
+  fn reply(method: &str, params: &[Value]) -> Value {
+    assert_eq!(params[0], format!("token:{}", SECRET), "{}", method);
+    match method {
+      "aria2.addUri" => {
+        let out = params[2]["out"].as_str().unwrap();
+        json!(format!("gid-{}", &out[3..7]))
+      }
+      "aria2.tellStatus" => match params[1].as_str().unwrap() {
+        "gid-0000" => json!([{ "status": "complete" }]),
+        "gid-0001" => json!([{
+          "status": "error",
+          "errorCode": "24",
+          "errorMessage": "Authorization failed.",
+        }]),
+        gid => json!({
+          "code": 1,
+          "message": format!("GID {} is not found", gid),
+        }),
+      },
+      "aria2.removeDownloadResult" => json!("OK"),
+      _ => panic!("unexpected method {}", method),
+    }
+  }
+
+  async fn serve(mut socket: TcpStream, requests: Arc<Mutex<Vec<Value>>>) {
+    let mut buffer = Vec::new();
+    let body = loop {
+      let mut chunk = [0; 4096];
+      let read = socket.read(&mut chunk).await.unwrap();
+      assert!(read > 0, "request cut short");
+      buffer.extend_from_slice(&chunk[..read]);
+
+      let text = String::from_utf8_lossy(&buffer);
+      let Some(end) = text.find("\r\n\r\n") else {
+        continue;
+      };
+      let length: usize = text[..end]
+        .lines()
+        .find_map(|line| {
+          let (name, value) = line.split_once(':')?;
+          name
+            .eq_ignore_ascii_case("content-length")
+            .then(|| value.trim().parse().unwrap())
+        })
+        .unwrap();
+      if buffer.len() >= end + 4 + length {
+        break buffer[end + 4..end + 4 + length].to_vec();
+      }
+    };
+
+    let request: Value = serde_json::from_slice(&body).unwrap();
+    let method = request["method"].as_str().unwrap();
+    let params = request["params"].as_array().unwrap();
+    let result = if method == "system.multicall" {
+      json!(params[0]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|call| reply(
+          call["methodName"].as_str().unwrap(),
+          call["params"].as_array().unwrap()
+        ))
+        .collect::<Vec<Value>>())
+    } else {
+      reply(method, params)
+    };
+    requests.lock().unwrap().push(request.clone());
+
+    let reply =
+      json!({ "jsonrpc": "2.0", "id": request["id"], "result": result })
+        .to_string();
+    let response = format!(
+      "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+       Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+      reply.len(),
+      reply
+    );
+    socket.write_all(response.as_bytes()).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn polls_segments_until_done_or_failed() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/jsonrpc", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let served = requests.clone();
+    tokio::spawn(async move {
+      loop {
+        let (socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(serve(socket, served.clone()));
+      }
+    });
+
+    let client = Client::new();
+    let downloader = Aria2Downloader::new(
+      &Aria2Options {
+        url,
+        secret: Some(SECRET.to_string()),
+      },
+      HttpDownloader::new(&client, 1, false, false),
+    );
+    let dir = env::temp_dir();
+    let jobs = (0..3)
+      .map(|index| SegmentJob {
+        index,
+        url: format!("http://video.example/seg{}.ts", index),
+        byte_range: None,
+        filename: format!("{}/seg{:04}.ts", dir.display(), index),
+      })
+      .collect();
+
+    let mut results: Vec<_> = downloader.download(jobs).collect().await;
+    results.sort_by_key(|result| match result {
+      Ok(segment) => segment.index,
+      Err(failure) => failure.index,
+    });
+    assert!(matches!(&results[0], Ok(segment) if segment.index == 0));
+    assert!(matches!(&results[1], Err(f) if f.index == 1 && f.unauthorized));
+    assert!(matches!(&results[2], Err(f) if f.index == 2 && !f.unauthorized));
+
+    let requests = requests.lock().unwrap();
+    let added: Vec<&Value> = requests
+      .iter()
+      .filter(|r| r["method"] == "aria2.addUri")
+      .collect();
+    assert_eq!(added.len(), 3);
+    assert_eq!(added[0]["params"][1][0], "http://video.example/seg0.ts");
+    let options = &added[0]["params"][2];
+    assert_eq!(
+      options["dir"],
+      fs::canonicalize(&dir).unwrap().to_string_lossy().as_ref()
+    );
+    assert_eq!(options["out"], "seg0000.ts");
+    assert!(options["header"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .any(|h| h.as_str().unwrap().starts_with("Referer: ")));
+
+    // every finished gid is removed, the lost one included
+    let removed = requests
+      .iter()
+      .filter(|r| r["method"] == "aria2.removeDownloadResult")
+      .count();
+    assert_eq!(removed, 3);
+  }
+}
@@ -0,0 +1,170 @@
+use std::{
+  sync::{Mutex, RwLock},
+  time::{Duration, Instant},
+};
+
+use chrono::{Local, Timelike};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::AsyncResult;
+
+// A time of day range with its own rate, e.g. unlimited from 01:00 to 07:00.
+// Ranges may wrap past midnight. A missing rate, "0" or "unlimited" lifts
+// the cap.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RateWindow {
+  pub from: String,
+  pub to: String,
+  pub rate: Option<String>,
+}
+
+struct Window {
+  // minutes since midnight
+  from: u32,
+  to: u32,
+  rate: Option<u64>,
+}
+
+impl Window {
+  fn contains(&self, minute: u32) -> bool {
+    if self.from <= self.to {
+      (self.from..self.to).contains(&minute)
+    } else {
+      minute >= self.from || minute < self.to
+    }
+  }
+}
+
+struct Limits {
+  default: Option<u64>,
+  windows: Vec<Window>,
+}
+
+struct Bucket {
+  rate: Option<u64>,
+  tokens: f64,
+  refilled: Instant,
+}
+
+static LIMITS: RwLock<Option<Limits>> = RwLock::new(None);
+static BUCKET: Mutex<Option<Bucket>> = Mutex::new(None);
+
+// Bytes per second from curl style rates: 500K, 5M, 1.5M or plain bytes.
+// Zero and "unlimited" mean no cap.
+pub fn parse_rate(rate: &str) -> Option<Option<u64>> {
+  let rate = rate.trim();
+  if rate.eq_ignore_ascii_case("unlimited") {
+    return Some(None);
+  }
+
+  let (number, unit) = match rate.char_indices().last()? {
+    (i, c) if c.is_ascii_alphabetic() => (&rate[..i], c.to_ascii_uppercase()),
+    _ => (rate, 'B'),
+  };
+  let multiplier = match unit {
+    'B' => 1.0,
+    'K' => 1024.0,
+    'M' => 1024.0 * 1024.0,
+    'G' => 1024.0 * 1024.0 * 1024.0,
+    _ => return None,
+  };
+  let bytes = number.parse::<f64>().ok()? * multiplier;
+  if !bytes.is_finite() || bytes < 0.0 {
+    return None;
+  }
+  Some(Some(bytes as u64).filter(|&b| b > 0))
+}
+
+fn parse_time_of_day(time: &str) -> Option<u32> {
+  let (hours, minutes) = time.trim().split_once(':')?;
+  let hours = hours.parse::<u32>().ok()?;
+  let minutes = minutes.parse::<u32>().ok()?;
+  (hours <= 24 && minutes < 60).then_some((hours * 60 + minutes) % (24 * 60))
+}
+
+// Sets the cap shared by every response body. `default` applies outside of
+// the schedule's windows.
+pub fn configure(
+  default: Option<&str>,
+  schedule: &[RateWindow],
+) -> AsyncResult<()> {
+  let default = match default {
+    Some(rate) => parse_rate(rate).ok_or(format!("invalid rate {}", rate))?,
+    None => None,
+  };
+
+  let mut windows = Vec::new();
+  for window in schedule {
+    let invalid = || format!("invalid schedule {}-{}", window.from, window.to);
+    let rate = match &window.rate {
+      Some(rate) => parse_rate(rate).ok_or(format!("invalid rate {}", rate))?,
+      None => None,
+    };
+    windows.push(Window {
+      from: parse_time_of_day(&window.from).ok_or_else(invalid)?,
+      to: parse_time_of_day(&window.to).ok_or_else(invalid)?,
+      rate,
+    });
+  }
+
+  *LIMITS.write().unwrap() = Some(Limits { default, windows });
+  Ok(())
+}
+
+fn current_rate() -> Option<u64> {
+  let limits = LIMITS.read().unwrap();
+  let limits = limits.as_ref()?;
+  if limits.windows.is_empty() {
+    return limits.default;
+  }
+
+  let now = Local::now();
+  let minute = now.hour() * 60 + now.minute();
+  match limits.windows.iter().find(|w| w.contains(minute)) {
+    Some(window) => window.rate,
+    None => limits.default,
+  }
+}
+
+fn format_rate(rate: Option<u64>) -> String {
+  match rate {
+    Some(rate) => format!("{:.1} MiB/s", rate as f64 / 1024.0 / 1024.0),
+    None => "unlimited".to_string(),
+  }
+}
+
+// Accounts for `bytes` just received and waits as long as the combined rate
+// of every download is over the cap
+pub async fn consume(bytes: usize) {
+  let rate = current_rate();
+
+  let wait = {
+    let mut bucket = BUCKET.lock().unwrap();
+    let bucket = bucket.get_or_insert_with(|| Bucket {
+      rate: None,
+      tokens: 0.0,
+      refilled: Instant::now(),
+    });
+    if bucket.rate != rate {
+      info!("bandwidth limit {}", format_rate(rate));
+      bucket.rate = rate;
+      bucket.tokens = rate.unwrap_or(0) as f64;
+      bucket.refilled = Instant::now();
+    }
+    let Some(rate) = rate else { return };
+
+    // at most a second worth of burst
+    let rate = rate as f64;
+    let elapsed = bucket.refilled.elapsed().as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+    bucket.refilled = Instant::now();
+    bucket.tokens -= bytes as f64;
+
+    if bucket.tokens >= 0.0 {
+      return;
+    }
+    Duration::from_secs_f64(-bucket.tokens / rate)
+  };
+  tokio::time::sleep(wait).await;
+}
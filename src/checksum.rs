@@ -0,0 +1,248 @@
+use std::{
+  collections::BTreeMap,
+  fs::{self, File},
+  io,
+  path::{Path, PathBuf},
+};
+
+use log::debug;
+use sha2::{Digest, Sha256};
+
+use crate::{
+  repair::{self, SegmentMap},
+  AsyncResult,
+};
+
+// one per series directory, in the format of sha256sum
+pub const MANIFEST: &str = "SHA256SUMS";
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+  let mut hasher = Sha256::new();
+  io::copy(&mut File::open(path)?, &mut hasher)?;
+  Ok(format!("{:x}", hasher.finalize()))
+}
+
+// The manifest next to `file` and the name `file` is listed under
+fn manifest_for(file: &Path) -> io::Result<(PathBuf, String)> {
+  let name = file.file_name().and_then(|n| n.to_str()).ok_or_else(|| {
+    io::Error::new(io::ErrorKind::InvalidInput, "file name is not utf-8")
+  })?;
+  let dir = file.parent().unwrap_or(Path::new(""));
+  Ok((dir.join(MANIFEST), name.to_string()))
+}
+
+// name -> hash from "<hash>  <name>" lines, "*" marks binary mode
+fn read_manifest(path: &Path) -> io::Result<BTreeMap<String, String>> {
+  if !path.exists() {
+    return Ok(BTreeMap::new());
+  }
+  Ok(
+    fs::read_to_string(path)?
+      .lines()
+      .filter_map(|line| {
+        let (hash, name) = line.split_once(' ')?;
+        let name = name.strip_prefix(' ').or_else(|| name.strip_prefix('*'))?;
+        Some((name.to_string(), hash.to_ascii_lowercase()))
+      })
+      .collect(),
+  )
+}
+
+fn write_manifest(
+  path: &Path,
+  entries: &BTreeMap<String, String>,
+) -> io::Result<()> {
+  let content: String = entries
+    .iter()
+    .map(|(name, hash)| format!("{}  {}\n", hash, name))
+    .collect();
+  fs::write(path, content)
+}
+
+// Adds `file` to the SHA256SUMS in its directory, replacing an older entry
+pub fn record(file: &Path) -> io::Result<()> {
+  let (manifest, name) = manifest_for(file)?;
+  let hash = sha256_file(file)?;
+  debug!("sha256 {} {}", hash, file.display());
+
+  let mut entries = read_manifest(&manifest)?;
+  entries.insert(name, hash);
+  write_manifest(&manifest, &entries)
+}
+
+// Re-hashes `file` after it was changed on purpose, if it is listed at all
+pub fn update(file: &Path) -> io::Result<()> {
+  let (manifest, name) = manifest_for(file)?;
+  if read_manifest(&manifest)?.contains_key(&name) {
+    record(file)?;
+  }
+  Ok(())
+}
+
+fn collect_manifests(
+  path: &Path,
+  manifests: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+  if path.is_dir() {
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+      .filter_map(|entry| entry.ok().map(|e| e.path()))
+      .collect();
+    entries.sort();
+    for entry in entries {
+      collect_manifests(&entry, manifests)?;
+    }
+  } else if path.file_name().is_some_and(|n| n == MANIFEST) {
+    manifests.push(path.to_path_buf());
+  }
+  Ok(())
+}
+
+// What is wrong with a listed file, None when it still matches
+fn check_file(file: &Path, expected: &str) -> io::Result<Option<String>> {
+  if !file.exists() {
+    return Ok(Some("missing".to_string()));
+  }
+  if sha256_file(file)? == expected {
+    return Ok(None);
+  }
+
+  // the segment map narrows it down to what --repair would re-fetch
+  let Some(map) = SegmentMap::load(file) else {
+    return Ok(Some("checksum mismatch".to_string()));
+  };
+  let changed: Vec<usize> = repair::changed_segments(file, &map)?
+    .into_iter()
+    .map(|position| map.segments[position].index)
+    .collect();
+  Ok(Some(if changed.is_empty() {
+    "checksum mismatch outside of the segments".to_string()
+  } else {
+    format!("checksum mismatch in segments {:?}", changed)
+  }))
+}
+
+// Checks every file listed in the SHA256SUMS under a directory (or a single
+// listed file) and returns how many were checked and the failed ones with
+// the reason.
+pub fn check_library(
+  path: &Path,
+) -> AsyncResult<(usize, Vec<(PathBuf, String)>)> {
+  let mut listed = Vec::new();
+  if path.is_dir() {
+    let mut manifests = Vec::new();
+    collect_manifests(path, &mut manifests)?;
+    for manifest in manifests {
+      let dir = manifest.parent().unwrap_or(Path::new(""));
+      for (name, hash) in read_manifest(&manifest)? {
+        listed.push((dir.join(name), hash));
+      }
+    }
+  } else {
+    let (manifest, name) = manifest_for(path)?;
+    let Some(hash) = read_manifest(&manifest)?.remove(&name) else {
+      return Err(format!("{} is not in {}", name, manifest.display()).into());
+    };
+    listed.push((path.to_path_buf(), hash));
+  }
+  if listed.is_empty() {
+    return Err(format!("no {} under {}", MANIFEST, path.display()).into());
+  }
+
+  let mut failures = Vec::new();
+  for (file, hash) in &listed {
+    if let Some(reason) = check_file(file, hash)? {
+      failures.push((file.clone(), reason));
+    }
+  }
+  Ok((listed.len(), failures))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+
+  use super::*;
+
+  // A fresh directory under the temp dir
+  fn series_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("anilife-dl-checksum-{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  #[test]
+  fn record_and_check() {
+    let dir = series_dir("record");
+    let first = dir.join("01-Episode 1.ts");
+    let second = dir.join("02-Episode 2.ts");
+    fs::write(&first, b"first").unwrap();
+    fs::write(&second, b"second").unwrap();
+    record(&second).unwrap();
+    record(&first).unwrap();
+
+    let manifest = fs::read_to_string(dir.join(MANIFEST)).unwrap();
+    assert_eq!(
+      manifest,
+      format!(
+        "{}  01-Episode 1.ts\n{}  02-Episode 2.ts\n",
+        repair::sha256(b"first"),
+        repair::sha256(b"second")
+      )
+    );
+    let (checked, failures) = check_library(&dir).unwrap();
+    assert_eq!(checked, 2);
+    assert!(failures.is_empty());
+
+    fs::write(&second, b"changed").unwrap();
+    fs::remove_file(&first).unwrap();
+    let (_, failures) = check_library(&dir).unwrap();
+    let reasons: Vec<&str> = failures.iter().map(|(_, r)| r.as_str()).collect();
+    assert_eq!(reasons, ["missing", "checksum mismatch"]);
+  }
+
+  #[test]
+  fn update_replaces_listed_entries_only() {
+    let dir = series_dir("update");
+    let listed = dir.join("01-Episode 1.ts");
+    let unlisted = dir.join("02-Episode 2.ts");
+    fs::write(&listed, b"before").unwrap();
+    fs::write(&unlisted, b"other").unwrap();
+    record(&listed).unwrap();
+
+    fs::write(&listed, b"after").unwrap();
+    update(&listed).unwrap();
+    update(&unlisted).unwrap();
+    let entries = read_manifest(&dir.join(MANIFEST)).unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries["01-Episode 1.ts"], repair::sha256(b"after"));
+  }
+
+  #[test]
+  fn reads_sha256sum_output() {
+    let dir = series_dir("format");
+    let text = repair::sha256(b"text");
+    let binary = repair::sha256(b"binary");
+    fs::write(
+      dir.join(MANIFEST),
+      format!(
+        "{}  text mode.ts\n{} *binary mode.ts\n{}  *star.ts\nnot a line\n",
+        text.to_ascii_uppercase(),
+        binary,
+        text
+      ),
+    )
+    .unwrap();
+
+    let entries = read_manifest(&dir.join(MANIFEST)).unwrap();
+    let expected: BTreeMap<String, String> = [
+      ("text mode.ts", &text),
+      ("binary mode.ts", &binary),
+      ("*star.ts", &text),
+    ]
+    .into_iter()
+    .map(|(name, hash)| (name.to_string(), hash.clone()))
+    .collect();
+    assert_eq!(entries, expected);
+  }
+}
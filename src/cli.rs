@@ -2,6 +2,12 @@ use std::env::Args;
 
 use log::{error, info};
 
+use crate::{
+  bandwidth,
+  export::ExportFormat,
+  http::{self, IpVersion},
+};
+
 pub fn print_help() {
   println!("anime-dl");
   println!("Usage: ");
@@ -11,6 +17,15 @@ pub fn print_help() {
     "  anime-dl --anime <anime_id> --d <episode_num1>,<episode_num2>,..."
   );
   println!("  anime-dl --anime <anime_id> --all");
+  println!("  anime-dl --anime <anime_id> --info <episode_num1>,...");
+  println!("  anime-dl --anime <anime_id> --resolve <episode_num1>,...");
+  println!("  anime-dl --anime <anime_id> --play <episode_num1>,...");
+  println!("  anime-dl --inspect <player.html>");
+  println!("  anime-dl --login <username>");
+  println!("  anime-dl --logout");
+  println!("  anime-dl --verify <file|dir>");
+  println!("  anime-dl --repair <file>");
+  println!("  anime-dl --check <file|dir>");
   println!("Options: ");
   println!("  -h --help      Show this screen");
   println!("  -s --search    Search anime with title");
@@ -19,6 +34,64 @@ pub fn print_help() {
   println!("  -l --list      List episodes of anime");
   println!("  -d --download  Download episode of that index");
   println!("  --all          Download all episodes");
+  println!("  -i --info      Show qualities offered by episodes");
+  println!("  --resolve      Print stream urls for other tools, see --format");
+  println!("  --play         Stream episodes to a player without saving");
+  println!("  --inspect      Extract player data from a saved player page");
+  println!("  --login        Log in ($ANILIFE_PASSWORD or prompt)");
+  println!("  --logout       Forget the stored session");
+  println!("  --verify       Check downloaded .ts files, list suspect ones");
+  println!("  --repair       Re-fetch the bad segments of a mapped download");
+  println!("  --check        Check files against their SHA256SUMS");
+  println!("  -q --quality <q>         Pin a quality (1080, 720, ...)");
+  println!("  --format <f>             url, curl, aria2, yt-dlp or m3u8");
+  println!("  --player <command>       Player for --play (default mpv -)");
+  println!("  --stdout                 Write --play to stdout instead");
+  println!("  -m --max-concurrent <n>  Concurrent segment downloads");
+  println!("  --adaptive               Tune concurrency, -m is the ceiling");
+  println!(
+    "  --hedge                  Race a second request for slow last segments"
+  );
+  println!("  --audio <lang,..>        Download alternate audio renditions");
+  println!("  --subs <lang,..>         Download subtitle renditions");
+  println!("  --mux                    Mux renditions into one mkv (ffmpeg)");
+  println!("  --record                 Follow a live playlist until it ends");
+  println!("  --record-timeout <secs>  Stop recording after <secs>");
+  println!("  --from <[hh:]mm:ss>      Download only from this time");
+  println!("  --to <[hh:]mm:ss>        Download only up to this time");
+  println!("  --trim                   Cut the clip at keyframes (ffmpeg)");
+  println!("  --segment-map            Keep a segment map for --repair");
+  println!("  --checksums              Add files to SHA256SUMS, keep a map");
+  println!("  --aria2 <rpc url>         Hand segments to an aria2 daemon");
+  println!("  --aria2-secret <secret>  The daemon's --rpc-secret");
+  println!(
+    "  --dry-run                Print the plan and sizes, download nothing"
+  );
+  println!(
+    "  --force                  Download even if the estimate does not fit"
+  );
+  println!("  --debug-dump <dir>       Save resolution pages and responses");
+  println!(
+    "  --host <url>             Site host (default https://anilife.live)"
+  );
+  println!("  --mirror <url,..>        Fallback mirror hosts");
+  println!(
+    "  --cookies <cookies.txt>  Import browser cookies (Netscape format)"
+  );
+  println!("  --user-agent <ua>        User agent to send, match the browser");
+  println!("  --user-agents <file>     Rotate user agents from a file");
+  println!("  --proxy <url>            http, https, socks5 or socks5h proxy");
+  println!("  --connect-timeout <secs> Give up connecting after <secs>");
+  println!("  --read-timeout <secs>    Give up when a response stalls <secs>");
+  println!("  --timeout <secs>         Give up on a request after <secs>");
+  println!(
+    "  --min-speed <rate>       Retry segments slower than this (4K, 0: off)"
+  );
+  println!("  -H --header <name: val>  Send an extra header, repeatable");
+  println!("  -4 / -6                  Prefer IPv4 / IPv6");
+  println!("  --page-rate <rps>        Site requests per second (default 2)");
+  println!("  --segment-rate <rps>     Video host requests per second (20)");
+  println!("  --limit-rate <rate>      Cap all downloads together, e.g. 5M");
 }
 
 pub enum CommandType {
@@ -26,6 +99,15 @@ pub enum CommandType {
   List,
   Download,
   DownloadAll,
+  Info,
+  Resolve,
+  Play,
+  Inspect,
+  Login,
+  Logout,
+  Verify,
+  Repair,
+  Check,
   Top,
   New,
   Concat,
@@ -38,7 +120,44 @@ pub struct CommandArgs {
   pub query: String,
   pub episode_nums: Vec<String>,
   pub filename: String,
+  pub username: String,
   pub max_concurrent: usize,
+  pub adaptive: bool,
+  pub hedge: bool,
+  pub quality: Option<String>,
+  pub format: Option<ExportFormat>,
+  pub player: Option<String>,
+  pub stdout: bool,
+  pub audio: Vec<String>,
+  pub subs: Vec<String>,
+  pub mux: bool,
+  pub record: bool,
+  pub record_timeout: Option<u64>,
+  pub from: Option<f64>,
+  pub to: Option<f64>,
+  pub trim: bool,
+  pub segment_map: bool,
+  pub checksums: bool,
+  pub dry_run: bool,
+  pub force: bool,
+  pub aria2: Option<String>,
+  pub aria2_secret: Option<String>,
+  pub debug_dump: Option<String>,
+  pub host: Option<String>,
+  pub mirrors: Vec<String>,
+  pub cookies: Option<String>,
+  pub user_agent: Option<String>,
+  pub user_agents: Option<String>,
+  pub proxy: Option<String>,
+  pub connect_timeout: Option<u64>,
+  pub read_timeout: Option<u64>,
+  pub min_speed: Option<String>,
+  pub timeout: Option<u64>,
+  pub headers: Vec<(String, String)>,
+  pub ip_version: Option<IpVersion>,
+  pub page_rate: Option<f64>,
+  pub segment_rate: Option<f64>,
+  pub limit_rate: Option<String>,
 }
 
 pub struct Command {
@@ -57,8 +176,10 @@ pub fn parse_args(mut args: Args) -> Result<Command, String> {
   }
 
   let mut command_type = CommandType::Help;
-  let mut command_args = CommandArgs::default();
-  command_args.max_concurrent = DEFAULT_MAX_CONCURRENT;
+  let mut command_args = CommandArgs {
+    max_concurrent: DEFAULT_MAX_CONCURRENT,
+    ..Default::default()
+  };
 
   while let Some(arg) = args.next() {
     match arg.as_str() {
@@ -112,6 +233,80 @@ pub fn parse_args(mut args: Args) -> Result<Command, String> {
         command_type = CommandType::Download;
         command_args.episode_nums = episode_num_vec;
       }
+      "-i" | "--info" => {
+        let episode_nums = match args.next() {
+          Some(i) => i,
+          None => {
+            error!("Episode num is missing");
+            return Err("error".to_string());
+          }
+        };
+
+        command_type = CommandType::Info;
+        command_args.episode_nums =
+          episode_nums.split(',').map(|e| e.to_string()).collect();
+      }
+      "--resolve" => {
+        let episode_nums = match args.next() {
+          Some(i) => i,
+          None => {
+            error!("Episode num is missing");
+            return Err("error".to_string());
+          }
+        };
+
+        command_type = CommandType::Resolve;
+        command_args.episode_nums =
+          episode_nums.split(',').map(|e| e.to_string()).collect();
+      }
+      "--play" => {
+        let episode_nums = match args.next() {
+          Some(i) => i,
+          None => {
+            error!("Episode num is missing");
+            return Err("error".to_string());
+          }
+        };
+
+        command_type = CommandType::Play;
+        command_args.episode_nums =
+          episode_nums.split(',').map(|e| e.to_string()).collect();
+      }
+      "--player" => {
+        let player = match args.next() {
+          Some(p) => p,
+          None => {
+            error!("player command is missing");
+            return Err("player command is missing".to_string());
+          }
+        };
+        command_args.player = Some(player);
+      }
+      "--stdout" => {
+        command_args.stdout = true;
+      }
+      "--format" => {
+        let format = match args.next().as_deref().map(ExportFormat::parse) {
+          Some(Some(f)) => f,
+          _ => {
+            error!("--format expects url, curl, aria2, yt-dlp or m3u8");
+            return Err(
+              "--format expects url, curl, aria2, yt-dlp or m3u8".to_string(),
+            );
+          }
+        };
+        command_args.format = Some(format);
+      }
+      "-q" | "--quality" => {
+        let quality = match args.next() {
+          Some(q) => q,
+          None => {
+            error!("quality is missing");
+            return Err("quality is missing".to_string());
+          }
+        };
+        command_args.quality = Some(quality);
+      }
       "-m" | "--max-concurrent" => {
         let max_concurrent = match args.next() {
           Some(m) => m.parse::<usize>().unwrap(),
@@ -122,9 +317,303 @@ pub fn parse_args(mut args: Args) -> Result<Command, String> {
         };
         command_args.max_concurrent = max_concurrent;
       }
+      "--adaptive" => {
+        command_args.adaptive = true;
+      }
+      "--hedge" => {
+        command_args.hedge = true;
+      }
+      "--min-speed" => {
+        let speed = match args.next() {
+          Some(s) if bandwidth::parse_rate(&s).is_some() => s,
+          _ => {
+            error!("--min-speed expects a rate like 4K");
+            return Err("--min-speed expects a rate like 4K".to_string());
+          }
+        };
+        command_args.min_speed = Some(speed);
+      }
+      "--audio" => {
+        let languages = match args.next() {
+          Some(l) => l,
+          None => {
+            error!("audio language is missing");
+            return Err("audio language is missing".to_string());
+          }
+        };
+        command_args.audio =
+          languages.split(',').map(|l| l.to_string()).collect();
+      }
+      "--subs" => {
+        let languages = match args.next() {
+          Some(l) => l,
+          None => {
+            error!("subtitle language is missing");
+            return Err("subtitle language is missing".to_string());
+          }
+        };
+        command_args.subs =
+          languages.split(',').map(|l| l.to_string()).collect();
+      }
+      "--mux" => {
+        command_args.mux = true;
+      }
+      "--record" => {
+        command_args.record = true;
+      }
+      "--record-timeout" => {
+        let timeout = match args.next().map(|t| t.parse::<u64>()) {
+          Some(Ok(t)) => t,
+          _ => {
+            error!("{} expects seconds", arg);
+            return Err(format!("{} expects seconds", arg));
+          }
+        };
+        command_args.record_timeout = Some(timeout);
+      }
+      "--from" | "--to" => {
+        let seconds = match args.next().as_deref().map(parse_timestamp) {
+          Some(Some(s)) => s,
+          _ => {
+            error!("{} expects a time like 12:30", arg);
+            return Err(format!("{} expects a time like 12:30", arg));
+          }
+        };
+        if arg == "--from" {
+          command_args.from = Some(seconds);
+        } else {
+          command_args.to = Some(seconds);
+        }
+      }
+      "--trim" => {
+        command_args.trim = true;
+      }
+      "--segment-map" => {
+        command_args.segment_map = true;
+      }
+      "--checksums" => {
+        command_args.checksums = true;
+      }
+      "--dry-run" => {
+        command_args.dry_run = true;
+      }
+      "--force" => {
+        command_args.force = true;
+      }
+      "--aria2" => {
+        let url = match args.next() {
+          Some(u) => u,
+          None => {
+            error!("aria2 rpc url is missing");
+            return Err("aria2 rpc url is missing".to_string());
+          }
+        };
+        command_args.aria2 = Some(url);
+      }
+      "--aria2-secret" => {
+        let secret = match args.next() {
+          Some(s) => s,
+          None => {
+            error!("aria2 rpc secret is missing");
+            return Err("aria2 rpc secret is missing".to_string());
+          }
+        };
+        command_args.aria2_secret = Some(secret);
+      }
+      "--debug-dump" => {
+        let dir = match args.next() {
+          Some(d) => d,
+          None => {
+            error!("debug dump directory is missing");
+            return Err("debug dump directory is missing".to_string());
+          }
+        };
+        command_args.debug_dump = Some(dir);
+      }
+      "--host" => {
+        let host = match args.next() {
+          Some(h) => h,
+          None => {
+            error!("host is missing");
+            return Err("host is missing".to_string());
+          }
+        };
+        command_args.host = Some(host);
+      }
+      "--mirror" => {
+        let mirrors = match args.next() {
+          Some(m) => m,
+          None => {
+            error!("mirror is missing");
+            return Err("mirror is missing".to_string());
+          }
+        };
+        command_args
+          .mirrors
+          .extend(mirrors.split(',').map(|m| m.to_string()));
+      }
+      "--cookies" => {
+        let cookies = match args.next() {
+          Some(c) => c,
+          None => {
+            error!("cookies file is missing");
+            return Err("cookies file is missing".to_string());
+          }
+        };
+        command_args.cookies = Some(cookies);
+      }
+      "--user-agent" => {
+        let user_agent = match args.next() {
+          Some(u) => u,
+          None => {
+            error!("user agent is missing");
+            return Err("user agent is missing".to_string());
+          }
+        };
+        command_args.user_agent = Some(user_agent);
+      }
+      "--user-agents" => {
+        let path = match args.next() {
+          Some(p) => p,
+          None => {
+            error!("user agent file is missing");
+            return Err("user agent file is missing".to_string());
+          }
+        };
+        command_args.user_agents = Some(path);
+      }
+      "--proxy" => {
+        let proxy = match args.next() {
+          Some(p) => p,
+          None => {
+            error!("proxy url is missing");
+            return Err("proxy url is missing".to_string());
+          }
+        };
+        command_args.proxy = Some(proxy);
+      }
+      "--connect-timeout" | "--read-timeout" | "--timeout" => {
+        let secs = match args.next().map(|t| t.parse::<u64>()) {
+          Some(Ok(t)) => t,
+          _ => {
+            error!("{} expects seconds", arg);
+            return Err(format!("{} expects seconds", arg));
+          }
+        };
+        match arg.as_str() {
+          "--connect-timeout" => command_args.connect_timeout = Some(secs),
+          "--read-timeout" => command_args.read_timeout = Some(secs),
+          _ => command_args.timeout = Some(secs),
+        }
+      }
+      "-H" | "--header" => {
+        let header = match args.next().as_deref().map(http::parse_header) {
+          Some(Some(h)) => h,
+          _ => {
+            error!("{} expects \"Name: value\"", arg);
+            return Err(format!("{} expects \"Name: value\"", arg));
+          }
+        };
+        command_args.headers.push(header);
+      }
+      "--page-rate" | "--segment-rate" => {
+        let rate = match args.next().map(|r| r.parse::<f64>()) {
+          Some(Ok(r)) => r,
+          _ => {
+            error!("{} expects requests per second", arg);
+            return Err(format!("{} expects requests per second", arg));
+          }
+        };
+        if arg == "--page-rate" {
+          command_args.page_rate = Some(rate);
+        } else {
+          command_args.segment_rate = Some(rate);
+        }
+      }
+      "--limit-rate" => {
+        let rate = match args.next() {
+          Some(r) if bandwidth::parse_rate(&r).is_some() => r,
+          _ => {
+            error!("--limit-rate expects a rate like 500K or 5M");
+            return Err(
+              "--limit-rate expects a rate like 500K or 5M".to_string(),
+            );
+          }
+        };
+        command_args.limit_rate = Some(rate);
+      }
+      "-4" => {
+        command_args.ip_version = Some(IpVersion::V4);
+      }
+      "-6" => {
+        command_args.ip_version = Some(IpVersion::V6);
+      }
       "--all" => {
         command_type = CommandType::DownloadAll;
       }
+      "--inspect" => {
+        let filename = match args.next() {
+          Some(f) => f,
+          None => {
+            error!("player page file is missing");
+            return Err("player page file is missing".to_string());
+          }
+        };
+
+        command_type = CommandType::Inspect;
+        command_args.filename = filename;
+      }
+      "--login" => {
+        let username = match args.next() {
+          Some(u) => u,
+          None => {
+            error!("username is missing");
+            return Err("username is missing".to_string());
+          }
+        };
+
+        command_type = CommandType::Login;
+        command_args.username = username;
+      }
+      "--verify" => {
+        let path = match args.next() {
+          Some(p) => p,
+          None => {
+            error!("file or directory to verify is missing");
+            return Err("file or directory to verify is missing".to_string());
+          }
+        };
+
+        command_type = CommandType::Verify;
+        command_args.filename = path;
+      }
+      "--repair" => {
+        let path = match args.next() {
+          Some(p) => p,
+          None => {
+            error!("file to repair is missing");
+            return Err("file to repair is missing".to_string());
+          }
+        };
+
+        command_type = CommandType::Repair;
+        command_args.filename = path;
+      }
+      "--check" => {
+        let path = match args.next() {
+          Some(p) => p,
+          None => {
+            error!("file or directory to check is missing");
+            return Err("file or directory to check is missing".to_string());
+          }
+        };
+
+        command_type = CommandType::Check;
+        command_args.filename = path;
+      }
+      "--logout" => {
+        command_type = CommandType::Logout;
+      }
       "--concat" => {
         command_type = CommandType::Concat;
       }
@@ -132,12 +621,95 @@ pub fn parse_args(mut args: Args) -> Result<Command, String> {
     }
   }
 
+  if let (Some(from), Some(to)) = (command_args.from, command_args.to) {
+    if from >= to {
+      error!("--from must be before --to");
+      return Err("--from must be before --to".to_string());
+    }
+  }
+
+  // a recording is written as it arrives, none of these apply
+  if command_args.record {
+    let unsupported = [
+      (
+        command_args.from.is_some() || command_args.to.is_some(),
+        "--from/--to",
+      ),
+      (
+        !command_args.audio.is_empty() || !command_args.subs.is_empty(),
+        "--audio/--subs",
+      ),
+      (command_args.mux, "--mux"),
+      (command_args.segment_map, "--segment-map"),
+      (command_args.checksums, "--checksums"),
+    ];
+    if let Some((_, flag)) = unsupported.iter().find(|(set, _)| *set) {
+      error!("--record does not support {}", flag);
+      return Err(format!("--record does not support {}", flag));
+    }
+  }
+
   Ok(Command {
     t: command_type,
     args: command_args,
   })
 }
 
+// [[hh:]mm:]ss[.fff] to seconds, minutes and seconds after a colon below 60
+pub fn parse_timestamp(value: &str) -> Option<f64> {
+  let digits = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+  let parts: Vec<&str> = value.split(':').collect();
+  let (seconds, units) = parts.split_last()?;
+  if units.len() > 2 {
+    return None;
+  }
+
+  let mut total = 0;
+  for (i, unit) in units.iter().enumerate() {
+    if !digits(unit) {
+      return None;
+    }
+    let unit = unit.parse::<u64>().ok()?;
+    if i > 0 && unit >= 60 {
+      return None;
+    }
+    total = total * 60 + unit;
+  }
+
+  let (whole, fraction) = seconds.split_once('.').unwrap_or((seconds, "0"));
+  if !digits(whole) || !digits(fraction) {
+    return None;
+  }
+  let seconds = seconds.parse::<f64>().ok()?;
+  if !units.is_empty() && seconds >= 60.0 {
+    return None;
+  }
+  Some(total as f64 * 60.0 + seconds)
+}
+
 pub fn print_progress(filename: &str, count: usize, len: usize) {
   info!("[{}/{}] {}", count, len, filename);
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn timestamps() {
+    assert_eq!(parse_timestamp("90"), Some(90.0));
+    assert_eq!(parse_timestamp("12:30"), Some(750.0));
+    assert_eq!(parse_timestamp("1:02:03.5"), Some(3723.5));
+    assert_eq!(parse_timestamp("0:00"), Some(0.0));
+  }
+
+  #[test]
+  fn bad_timestamps() {
+    for value in [
+      "", "inf", "nan", "-5", "1e3", "+5", "1:60", "1:60:00", "1:2:3:4", "12:",
+      ":30", "12.", "1.5:00", " 12",
+    ] {
+      assert_eq!(parse_timestamp(value), None, "{:?}", value);
+    }
+  }
+}
@@ -0,0 +1,162 @@
+use std::{
+  pin::pin,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use log::{debug, info};
+use tokio::sync::Notify;
+
+// where the adaptive mode starts
+const INITIAL_LIMIT: usize = 4;
+// throughput is compared over windows of this length
+const WINDOW: Duration = Duration::from_secs(3);
+// a window with more failed segments than this does not grow the limit
+const MAX_ERROR_RATIO: f64 = 0.05;
+// a window has to beat the last one by this factor to grow the limit
+const THROUGHPUT_GAIN: f64 = 1.02;
+
+struct State {
+  limit: usize,
+  active: usize,
+  peak: usize,
+  window_start: Instant,
+  window_bytes: u64,
+  window_done: usize,
+  window_errors: usize,
+  last_throughput: f64,
+  // one halving per window, a burst of timeouts is one signal
+  last_decrease: Option<Instant>,
+}
+
+// How many segments download at once. Fixed at `max` unless adaptive, then
+// it starts small, grows by one while throughput improves and errors stay
+// rare, and halves on timeouts and 429s (AIMD), never above `max`.
+pub struct Concurrency {
+  state: Mutex<State>,
+  notify: Notify,
+  max: usize,
+  adaptive: bool,
+}
+
+pub struct Permit<'a> {
+  concurrency: &'a Concurrency,
+}
+
+impl Drop for Permit<'_> {
+  fn drop(&mut self) {
+    self.concurrency.state.lock().unwrap().active -= 1;
+    self.concurrency.notify.notify_one();
+  }
+}
+
+impl Concurrency {
+  pub fn new(max: usize, adaptive: bool) -> Concurrency {
+    let max = max.max(1);
+    let limit = if adaptive {
+      INITIAL_LIMIT.min(max)
+    } else {
+      max
+    };
+    Concurrency {
+      state: Mutex::new(State {
+        limit,
+        active: 0,
+        peak: limit,
+        window_start: Instant::now(),
+        window_bytes: 0,
+        window_done: 0,
+        window_errors: 0,
+        last_throughput: 0.0,
+        last_decrease: None,
+      }),
+      notify: Notify::new(),
+      max,
+      adaptive,
+    }
+  }
+
+  pub async fn acquire(&self) -> Permit<'_> {
+    loop {
+      let mut notified = pin!(self.notify.notified());
+      notified.as_mut().enable();
+      {
+        let mut state = self.state.lock().unwrap();
+        if state.active < state.limit {
+          state.active += 1;
+          return Permit { concurrency: self };
+        }
+      }
+      notified.await;
+    }
+  }
+
+  // A segment of `bytes` finished
+  pub fn record_success(&self, bytes: usize) {
+    if !self.adaptive {
+      return;
+    }
+    let mut state = self.state.lock().unwrap();
+    state.window_bytes += bytes as u64;
+    state.window_done += 1;
+    self.end_window(&mut state);
+  }
+
+  // A segment failed, `throttled` for timeouts and 429s
+  pub fn record_failure(&self, throttled: bool) {
+    if !self.adaptive {
+      return;
+    }
+    let mut state = self.state.lock().unwrap();
+    state.window_errors += 1;
+
+    let recently_decreased =
+      state.last_decrease.is_some_and(|at| at.elapsed() < WINDOW);
+    if throttled && !recently_decreased && state.limit > 1 {
+      state.limit = (state.limit / 2).max(1);
+      state.last_decrease = Some(Instant::now());
+      debug!("throttled, concurrency down to {}", state.limit);
+    }
+  }
+
+  fn end_window(&self, state: &mut State) {
+    let elapsed = state.window_start.elapsed();
+    if elapsed < WINDOW {
+      return;
+    }
+
+    let throughput = state.window_bytes as f64 / elapsed.as_secs_f64();
+    let attempts = state.window_done + state.window_errors;
+    let error_ratio = state.window_errors as f64 / attempts.max(1) as f64;
+    let improved = throughput > state.last_throughput * THROUGHPUT_GAIN;
+
+    if improved && error_ratio <= MAX_ERROR_RATIO && state.limit < self.max {
+      state.limit += 1;
+      state.peak = state.peak.max(state.limit);
+      self.notify.notify_waiters();
+      debug!(
+        "{:.0} KiB/s, concurrency up to {}",
+        throughput / 1024.0,
+        state.limit
+      );
+    }
+
+    state.last_throughput = throughput;
+    state.window_start = Instant::now();
+    state.window_bytes = 0;
+    state.window_done = 0;
+    state.window_errors = 0;
+  }
+
+  // Logs where the adaptive mode ended up, to help pick a --max-concurrent
+  pub fn log_summary(&self) {
+    if !self.adaptive {
+      return;
+    }
+    let state = self.state.lock().unwrap();
+    info!(
+      "adaptive concurrency settled at {} (peak {}, max {})",
+      state.limit, state.peak, self.max
+    );
+  }
+}
@@ -0,0 +1,130 @@
+use std::{
+  env, fs, io,
+  path::{Path, PathBuf},
+};
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::http::HttpConfig;
+
+const CONFIG_ENV: &str = "ANILIFE_DL_CONFIG";
+pub const HOST_ENV: &str = "ANILIFE_HOST";
+pub const MIRRORS_ENV: &str = "ANILIFE_MIRRORS";
+
+// config.json in the user config directory, every field is optional
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+  pub host: Option<String>,
+  pub mirrors: Vec<String>,
+  // command for --play, reads the stream from stdin
+  pub player: Option<String>,
+  // user_agent, proxy, timeouts, ... sit at the top level of the file
+  #[serde(flatten)]
+  pub http: HttpConfig,
+}
+
+// %APPDATA%\anilife-dl on windows, $XDG_CONFIG_HOME/anilife-dl or
+// ~/.config/anilife-dl elsewhere
+pub fn config_dir() -> PathBuf {
+  let base = env::var_os("APPDATA")
+    .or_else(|| env::var_os("XDG_CONFIG_HOME"))
+    .map(PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+    .unwrap_or_else(|| PathBuf::from("."));
+  base.join("anilife-dl")
+}
+
+pub fn config_path() -> PathBuf {
+  match env::var_os(CONFIG_ENV) {
+    Some(path) => PathBuf::from(path),
+    None => config_dir().join("config.json"),
+  }
+}
+
+impl Config {
+  pub fn load() -> Config {
+    let path = config_path();
+    let content = match fs::read_to_string(&path) {
+      Ok(c) => c,
+      Err(_) => {
+        debug!("no config at {}", path.display());
+        return Config::default();
+      }
+    };
+
+    match serde_json::from_str(&content) {
+      Ok(config) => config,
+      Err(e) => {
+        warn!("ignoring invalid config {}: {}", path.display(), e);
+        Config::default()
+      }
+    }
+  }
+}
+
+// Keeps a permanent host move for the next runs. Only `host` is touched, a
+// config that does not parse is left alone rather than replaced.
+pub fn remember_host(host: &str) {
+  let path = config_path();
+  let mut config = match fs::read_to_string(&path) {
+    Ok(content) => match serde_json::from_str::<Value>(&content) {
+      Ok(Value::Object(config)) => config,
+      _ => {
+        warn!(
+          "not saving new host {}, {} is not valid json",
+          host,
+          path.display()
+        );
+        return;
+      }
+    },
+    Err(e) if e.kind() == io::ErrorKind::NotFound => Map::new(),
+    Err(e) => {
+      warn!("unable to read {}: {}", path.display(), e);
+      return;
+    }
+  };
+  config.insert("host".to_string(), Value::String(host.to_string()));
+
+  let saved = path
+    .parent()
+    .map_or(Ok(()), fs::create_dir_all)
+    .and_then(|()| Ok(serde_json::to_string_pretty(&config)?))
+    .and_then(|content| fs::write(&path, content));
+  if let Err(e) = saved {
+    warn!("unable to save new host to config: {}", e);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn remember_host_keeps_the_rest_of_the_config() {
+    let path = env::temp_dir().join("anilife-dl-remember-host.json");
+    env::set_var(CONFIG_ENV, &path);
+
+    fs::write(
+      &path,
+      r#"{"proxy": "socks5h://127.0.0.1:1080", "future": 1}"#,
+    )
+    .unwrap();
+    remember_host("https://moved.example");
+    let saved: Value =
+      serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(saved["host"], "https://moved.example");
+    assert_eq!(saved["proxy"], "socks5h://127.0.0.1:1080");
+    assert_eq!(saved["future"], 1);
+
+    let broken = r#"{"proxy": "socks5h://127.0.0.1:1080",}"#;
+    fs::write(&path, broken).unwrap();
+    remember_host("https://moved.example");
+    assert_eq!(fs::read_to_string(&path).unwrap(), broken);
+
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,335 @@
+use std::{
+  error::Error,
+  fs::File,
+  io::Write,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use futures::{
+  future::{self, Either},
+  stream::{self, BoxStream},
+  StreamExt,
+};
+use log::{info, warn};
+use reqwest::{Client, StatusCode};
+
+use crate::{
+  api,
+  concurrency::Concurrency,
+  hls::{ByteRange, MediaSegment},
+  http, AsyncResult,
+};
+
+// consecutive 401/403 responses before a playlist is considered expired
+const AUTH_FAILURE_RUN: usize = 5;
+// extra tries for a segment that stalled, timed out or lost its connection
+const SEGMENT_RETRIES: usize = 3;
+// hedging starts once this few segments are left and one of them takes
+// HEDGE_FACTOR times the average (at least HEDGE_MIN_WAIT)
+const HEDGE_TAIL: usize = 4;
+const HEDGE_FACTOR: u32 = 3;
+const HEDGE_MIN_WAIT: Duration = Duration::from_secs(2);
+const HEDGE_POLL: Duration = Duration::from_millis(250);
+
+// One segment to fetch into `filename`
+pub struct SegmentJob {
+  pub index: usize,
+  pub url: String,
+  pub byte_range: Option<ByteRange>,
+  pub filename: String,
+}
+
+pub struct Segment {
+  pub index: usize,
+  pub filename: String,
+}
+
+pub struct SegmentFailure {
+  pub index: usize,
+  // the playlist needs to be re-resolved before trying again
+  pub unauthorized: bool,
+}
+
+// Fetches segments into their staging files. download_playlist hands it one
+// pass over the playlist at a time, re-resolves on unauthorized failures and
+// combines the files itself.
+pub trait SegmentDownloader: Send + Sync {
+  // one result per job, in any order
+  fn download(
+    &self,
+    jobs: Vec<SegmentJob>,
+  ) -> BoxStream<'_, Result<Segment, SegmentFailure>>;
+
+  // after an episode, whatever is worth knowing about the run
+  fn log_summary(&self) {}
+}
+
+// The built in backend, reqwest through the rate and bandwidth limits
+pub struct HttpDownloader {
+  client: Client,
+  concurrency: Arc<Concurrency>,
+  hedge: bool,
+}
+
+impl HttpDownloader {
+  pub fn new(
+    client: &Client,
+    max_concurrent: usize,
+    adaptive: bool,
+    hedge: bool,
+  ) -> HttpDownloader {
+    HttpDownloader {
+      client: client.clone(),
+      concurrency: Arc::new(Concurrency::new(max_concurrent, adaptive)),
+      hedge,
+    }
+  }
+}
+
+impl SegmentDownloader for HttpDownloader {
+  fn download(
+    &self,
+    jobs: Vec<SegmentJob>,
+  ) -> BoxStream<'_, Result<Segment, SegmentFailure>> {
+    let pass = Arc::new(Pass::new(jobs.len(), self.hedge));
+    let tasks: Vec<_> = jobs
+      .into_iter()
+      .map(|job| {
+        let index = job.index;
+        let client = self.client.clone();
+        let concurrency = self.concurrency.clone();
+        let pass = pass.clone();
+        let task = tokio::spawn(async move {
+          download_segment(&client, job, &concurrency, &pass).await
+        });
+        (index, task)
+      })
+      .collect();
+
+    stream::iter(tasks)
+      .then(|(index, task)| async move {
+        task.await.unwrap_or_else(|e| {
+          warn!("segment {} failed: {}", index, e);
+          Err(SegmentFailure {
+            index,
+            unauthorized: false,
+          })
+        })
+      })
+      .boxed()
+  }
+
+  fn log_summary(&self) {
+    self.concurrency.log_summary();
+  }
+}
+
+// Shared by the segments of one pass over the playlist
+struct Pass {
+  // consecutive unauthorized segments, once it reaches AUTH_FAILURE_RUN the
+  // rest of the pass is not requested until the playlist is re-resolved
+  expired: AtomicUsize,
+  remaining: AtomicUsize,
+  // total time and count of finished segments
+  finished: Mutex<(Duration, u32)>,
+  hedge: bool,
+}
+
+impl Pass {
+  fn new(segments: usize, hedge: bool) -> Pass {
+    Pass {
+      expired: AtomicUsize::new(0),
+      remaining: AtomicUsize::new(segments),
+      finished: Mutex::new((Duration::ZERO, 0)),
+      hedge,
+    }
+  }
+
+  fn finish(&self, took: Option<Duration>) {
+    self.remaining.fetch_sub(1, Ordering::Relaxed);
+    if let Some(took) = took {
+      let mut finished = self.finished.lock().unwrap();
+      finished.0 += took;
+      finished.1 += 1;
+    }
+  }
+
+  // Near the end of the pass, a segment far slower than usual
+  fn should_hedge(&self, elapsed: Duration) -> bool {
+    if !self.hedge || self.remaining.load(Ordering::Relaxed) > HEDGE_TAIL {
+      return false;
+    }
+    let (total, count) = *self.finished.lock().unwrap();
+    count > 0 && elapsed > (total / count * HEDGE_FACTOR).max(HEDGE_MIN_WAIT)
+  }
+}
+
+// Timeouts and 429s mean too many requests at once
+fn is_throttled(e: &(dyn Error + 'static)) -> bool {
+  if e.is::<http::ReadTimeout>() {
+    return true;
+  }
+  e.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+    e.is_timeout() || e.status() == Some(StatusCode::TOO_MANY_REQUESTS)
+  })
+}
+
+fn is_unauthorized(e: &(dyn Error + 'static)) -> bool {
+  e.downcast_ref::<reqwest::Error>()
+    .and_then(|e| e.status())
+    .is_some_and(|status| {
+      status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN
+    })
+}
+
+// Worth trying again right away
+fn is_transient(e: &(dyn Error + 'static)) -> bool {
+  if is_throttled(e) || e.is::<http::Stalled>() {
+    return true;
+  }
+  e.downcast_ref::<reqwest::Error>().is_some_and(|e| {
+    e.is_connect()
+      || e.is_body()
+      || e.status().is_some_and(|s| s.is_server_error())
+  })
+}
+
+// Fetches a segment on its own, retrying transient failures
+pub async fn fetch_segment_retrying(
+  client: &Client,
+  index: usize,
+  segment: &MediaSegment,
+) -> AsyncResult<Vec<u8>> {
+  let mut attempt = 0;
+  loop {
+    let url = &segment.url;
+    match api::fetch_segment_bytes(client, url, segment.byte_range.as_ref())
+      .await
+    {
+      Ok(bytes) => return Ok(bytes),
+      Err(e) if !is_transient(e.as_ref()) || attempt == SEGMENT_RETRIES => {
+        return Err(e)
+      }
+      Err(e) => {
+        attempt += 1;
+        warn!(
+          "segment {} failed: {}, retrying ({}/{})",
+          index, e, attempt, SEGMENT_RETRIES
+        );
+        tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+      }
+    }
+  }
+}
+
+// Fetches a segment, racing a second request when it is one of the last
+// and takes unusually long. The first success wins.
+async fn fetch_segment_hedged(
+  client: &Client,
+  index: usize,
+  url: &str,
+  byte_range: Option<&ByteRange>,
+  pass: &Pass,
+) -> AsyncResult<Vec<u8>> {
+  let started = Instant::now();
+  let mut primary = Box::pin(api::fetch_segment_bytes(client, url, byte_range));
+  if !pass.hedge {
+    return primary.await;
+  }
+
+  let tail_reached = async {
+    loop {
+      tokio::time::sleep(HEDGE_POLL).await;
+      if pass.should_hedge(started.elapsed()) {
+        break;
+      }
+    }
+  };
+  tokio::select! {
+    result = &mut primary => return result,
+    _ = tail_reached => {}
+  }
+
+  info!("segment {} is slow, hedging with a second request", index);
+  let secondary = Box::pin(api::fetch_segment_bytes(client, url, byte_range));
+  let (first, other) = match future::select(primary, secondary).await {
+    Either::Left(done) | Either::Right(done) => done,
+  };
+  match first.ok() {
+    Some(bytes) => Ok(bytes),
+    None => other.await,
+  }
+}
+
+async fn download_segment(
+  client: &Client,
+  job: SegmentJob,
+  concurrency: &Concurrency,
+  pass: &Pass,
+) -> Result<Segment, SegmentFailure> {
+  let SegmentJob {
+    index,
+    url,
+    byte_range,
+    filename,
+  } = job;
+  let _permit = concurrency.acquire().await;
+  let started = Instant::now();
+
+  let mut attempt = 0;
+  let bytes = loop {
+    if pass.expired.load(Ordering::Relaxed) >= AUTH_FAILURE_RUN {
+      pass.finish(None);
+      return Err(SegmentFailure {
+        index,
+        unauthorized: true,
+      });
+    }
+
+    // the error is not Send, keep only what is needed past the await below
+    let (message, unauthorized, transient, throttled) =
+      match fetch_segment_hedged(client, index, &url, byte_range.as_ref(), pass)
+        .await
+      {
+        Ok(bytes) => break bytes,
+        Err(e) => (
+          e.to_string(),
+          is_unauthorized(e.as_ref()),
+          is_transient(e.as_ref()),
+          is_throttled(e.as_ref()),
+        ),
+      };
+
+    concurrency.record_failure(throttled);
+    if unauthorized {
+      pass.expired.fetch_add(1, Ordering::Relaxed);
+    }
+    if !transient || attempt == SEGMENT_RETRIES {
+      warn!("segment {} failed: {}", index, message);
+      pass.finish(None);
+      return Err(SegmentFailure {
+        index,
+        unauthorized,
+      });
+    }
+
+    attempt += 1;
+    warn!(
+      "segment {} failed: {}, retrying ({}/{})",
+      index, message, attempt, SEGMENT_RETRIES
+    );
+    tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+  };
+  pass.expired.store(0, Ordering::Relaxed);
+  pass.finish(Some(started.elapsed()));
+  concurrency.record_success(bytes.len());
+
+  let mut file = File::create(&filename).unwrap();
+  file.write_all(&bytes).unwrap();
+
+  Ok(Segment { index, filename })
+}
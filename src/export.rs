@@ -0,0 +1,108 @@
+#[derive(Clone, Copy)]
+pub enum ExportFormat {
+  Url,
+  Curl,
+  Aria2,
+  YtDlp,
+  M3u8,
+}
+
+impl ExportFormat {
+  pub fn parse(format: &str) -> Option<ExportFormat> {
+    match format.to_ascii_lowercase().as_str() {
+      "url" => Some(ExportFormat::Url),
+      "curl" => Some(ExportFormat::Curl),
+      "aria2" | "aria2c" => Some(ExportFormat::Aria2),
+      "yt-dlp" | "ytdlp" => Some(ExportFormat::YtDlp),
+      "m3u8" | "m3u" => Some(ExportFormat::M3u8),
+      _ => None,
+    }
+  }
+}
+
+pub struct ExportedStream {
+  // for output file names
+  pub filename: String,
+  // for the playlist entry
+  pub title: String,
+  pub url: String,
+}
+
+// single quotes for sh, closing and reopening around quotes in the value
+fn shell_quote(value: &str) -> String {
+  format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn header_value<'a>(
+  headers: &'a [(String, String)],
+  name: &str,
+) -> Option<&'a str> {
+  headers
+    .iter()
+    .find(|(n, _)| n.eq_ignore_ascii_case(name))
+    .map(|(_, v)| v.as_str())
+}
+
+// Prints resolved streams for a player or downloader, with the headers the
+// segments need
+pub fn print(
+  format: ExportFormat,
+  streams: &[ExportedStream],
+  headers: &[(String, String)],
+) {
+  if let ExportFormat::M3u8 = format {
+    println!("#EXTM3U");
+  }
+
+  for stream in streams {
+    match format {
+      ExportFormat::Url => println!("{}", stream.url),
+      ExportFormat::Curl => {
+        let mut command = String::from("curl -L");
+        for (name, value) in headers {
+          command +=
+            &format!(" -H {}", shell_quote(&format!("{}: {}", name, value)));
+        }
+        command += &format!(
+          " -o {} {}",
+          shell_quote(&format!("{}.m3u8", stream.filename)),
+          shell_quote(&stream.url)
+        );
+        println!("{}", command);
+      }
+      ExportFormat::Aria2 => {
+        println!("{}", stream.url);
+        for (name, value) in headers {
+          println!("  header={}: {}", name, value);
+        }
+        println!("  out={}.m3u8", stream.filename);
+      }
+      ExportFormat::YtDlp => {
+        let mut command = String::from("yt-dlp");
+        for (name, value) in headers {
+          command += &format!(
+            " --add-header {}",
+            shell_quote(&format!("{}:{}", name, value))
+          );
+        }
+        command += &format!(
+          " -o {} {}",
+          shell_quote(&format!("{}.%(ext)s", stream.filename)),
+          shell_quote(&stream.url)
+        );
+        println!("{}", command);
+      }
+      // vlc and mpv only take these two from a playlist
+      ExportFormat::M3u8 => {
+        println!("#EXTINF:-1,{}", stream.title);
+        if let Some(referer) = header_value(headers, "Referer") {
+          println!("#EXTVLCOPT:http-referrer={}", referer);
+        }
+        if let Some(user_agent) = header_value(headers, "User-Agent") {
+          println!("#EXTVLCOPT:http-user-agent={}", user_agent);
+        }
+        println!("{}", stream.url);
+      }
+    }
+  }
+}
@@ -0,0 +1,488 @@
+use std::collections::HashMap;
+
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+const HLS_SEG_TAG: &str = "#EXTINF";
+const HLS_MAP_TAG: &str = "#EXT-X-MAP";
+const HLS_BYTERANGE_TAG: &str = "#EXT-X-BYTERANGE";
+const HLS_TARGET_DURATION_TAG: &str = "#EXT-X-TARGETDURATION";
+const HLS_MEDIA_SEQUENCE_TAG: &str = "#EXT-X-MEDIA-SEQUENCE";
+const HLS_ENDLIST_TAG: &str = "#EXT-X-ENDLIST";
+const HLS_STREAM_INF_TAG: &str = "#EXT-X-STREAM-INF";
+const HLS_MEDIA_TAG: &str = "#EXT-X-MEDIA";
+
+#[derive(Clone)]
+pub struct ByteRange {
+  pub length: u64,
+  pub offset: u64,
+}
+
+impl ByteRange {
+  // <n>[@<o>], a missing offset continues from the previous sub-range.
+  // An empty range has no header value and is ignored.
+  fn parse(value: &str, next_offset: u64) -> Option<ByteRange> {
+    let mut parts = value.splitn(2, '@');
+    let length = parts.next()?.trim().parse::<u64>().ok()?;
+    if length == 0 {
+      return None;
+    }
+    let offset = match parts.next() {
+      Some(o) => o.trim().parse::<u64>().ok()?,
+      None => next_offset,
+    };
+    Some(ByteRange { length, offset })
+  }
+
+  pub fn header_value(&self) -> String {
+    format!("bytes={}-{}", self.offset, self.offset + self.length - 1)
+  }
+}
+
+pub struct InitSection {
+  pub url: String,
+  pub byte_range: Option<ByteRange>,
+}
+
+pub struct MediaSegment {
+  pub url: String,
+  pub duration: f64,
+  pub byte_range: Option<ByteRange>,
+}
+
+pub struct MediaPlaylist {
+  pub segments: Vec<MediaSegment>,
+  pub init: Option<InitSection>,
+  pub target_duration: f64,
+  pub media_sequence: u64,
+  // false for live playlists that are still growing
+  pub ended: bool,
+}
+
+// index of the segment playing at `time` seconds, given segment durations
+pub fn segment_at(
+  durations: impl Iterator<Item = f64>,
+  time: f64,
+) -> Option<usize> {
+  let mut start = 0.0;
+  for (index, duration) in durations.enumerate() {
+    if time < start + duration {
+      return Some(index);
+    }
+    start += duration;
+  }
+  None
+}
+
+impl MediaPlaylist {
+  fn any_segment_ends_with(&self, suffixes: &[&str]) -> bool {
+    self.segments.iter().any(|segment| {
+      let path = segment.url.split('?').next().unwrap_or("");
+      suffixes.iter().any(|suffix| path.ends_with(suffix))
+    })
+  }
+
+  // fMP4 segments need an init section and are not valid transport streams
+  pub fn is_fmp4(&self) -> bool {
+    self.init.is_some() || self.any_segment_ends_with(&[".m4s", ".mp4"])
+  }
+
+  pub fn duration(&self) -> f64 {
+    self.segments.iter().map(|segment| segment.duration).sum()
+  }
+
+  pub fn start_time(&self, index: usize) -> f64 {
+    self.segments[..index].iter().map(|s| s.duration).sum()
+  }
+
+  // index of the segment playing at `time` seconds
+  pub fn segment_at(&self, time: f64) -> Option<usize> {
+    segment_at(self.segments.iter().map(|s| s.duration), time)
+  }
+
+  // Index in this (re-resolved) playlist of segment `index` of an earlier
+  // one with `old` durations: the same layout keeps indices, otherwise the
+  // segment is matched by playback time
+  pub fn map_index(
+    &self,
+    old: impl ExactSizeIterator<Item = f64>,
+    index: usize,
+  ) -> Option<usize> {
+    if old.len() == self.segments.len() {
+      return Some(index);
+    }
+    self.segment_at(old.take(index).sum())
+  }
+
+  // Keeps only the segments overlapping [from, to) seconds and returns the
+  // original index of the first kept segment and its start time.
+  pub fn clip(&mut self, from: f64, to: f64) -> Option<(usize, f64)> {
+    let mut start = 0.0;
+    let mut first: Option<(usize, f64)> = None;
+    let mut kept = Vec::new();
+
+    for (index, segment) in self.segments.drain(..).enumerate() {
+      let end = start + segment.duration;
+      if end > from && start < to {
+        first.get_or_insert((index, start));
+        kept.push(segment);
+      }
+      start = end;
+    }
+
+    self.segments = kept;
+    first
+  }
+
+  pub fn extension(&self) -> &'static str {
+    if self.is_fmp4() {
+      "mp4"
+    } else if self.any_segment_ends_with(&[".vtt", ".webvtt"]) {
+      "vtt"
+    } else if self.any_segment_ends_with(&[".aac"]) {
+      "aac"
+    } else {
+      "ts"
+    }
+  }
+}
+
+pub fn parse_attributes(value: &str) -> HashMap<String, String> {
+  let mut attributes = HashMap::new();
+  let mut rest = value.trim();
+
+  while !rest.is_empty() {
+    let Some(eq) = rest.find('=') else { break };
+    let key = rest[..eq].trim().to_string();
+    rest = &rest[eq + 1..];
+
+    let value = if let Some(quoted) = rest.strip_prefix('"') {
+      let end = quoted.find('"').unwrap_or(quoted.len());
+      let value = quoted[..end].to_string();
+      rest = quoted.get(end + 1..).unwrap_or("");
+      value
+    } else {
+      let end = rest.find(',').unwrap_or(rest.len());
+      let value = rest[..end].to_string();
+      rest = &rest[end..];
+      value
+    };
+
+    attributes.insert(key, value);
+    rest = rest.trim_start_matches(',').trim_start();
+  }
+
+  attributes
+}
+
+pub fn resolve_url(base: &str, uri: &str) -> String {
+  match Url::parse(base).and_then(|base| base.join(uri)) {
+    Ok(url) => url.to_string(),
+    Err(_) => uri.to_string(),
+  }
+}
+
+fn tag_value<'a>(line: &'a str, tag: &str) -> Option<&'a str> {
+  line.strip_prefix(tag)?.strip_prefix(':')
+}
+
+pub fn parse_media_playlist(content: &str, base_url: &str) -> MediaPlaylist {
+  let mut segments = Vec::new();
+  let mut init = None;
+  let mut duration: Option<f64> = None;
+  let mut byte_range: Option<ByteRange> = None;
+  let mut next_offset = 0;
+  let mut target_duration = 0.0;
+  let mut media_sequence = 0;
+  let mut ended = false;
+
+  for line in content.lines().map(|l| l.trim()) {
+    if line.is_empty() {
+      continue;
+    }
+
+    if let Some(value) = tag_value(line, HLS_SEG_TAG) {
+      let value = value.split(',').next().unwrap_or("0");
+      duration = Some(value.trim().parse::<f64>().unwrap_or(0.0));
+    } else if let Some(value) = tag_value(line, HLS_MAP_TAG) {
+      let attributes = parse_attributes(value);
+      if let Some(uri) = attributes.get("URI") {
+        init = Some(InitSection {
+          url: resolve_url(base_url, uri),
+          byte_range: attributes
+            .get("BYTERANGE")
+            .and_then(|r| ByteRange::parse(r, 0)),
+        });
+      }
+    } else if let Some(value) = tag_value(line, HLS_TARGET_DURATION_TAG) {
+      target_duration = value.trim().parse::<f64>().unwrap_or(0.0);
+    } else if let Some(value) = tag_value(line, HLS_MEDIA_SEQUENCE_TAG) {
+      media_sequence = value.trim().parse::<u64>().unwrap_or(0);
+    } else if line == HLS_ENDLIST_TAG {
+      ended = true;
+    } else if let Some(value) = tag_value(line, HLS_BYTERANGE_TAG) {
+      byte_range = ByteRange::parse(value, next_offset);
+      if let Some(range) = &byte_range {
+        next_offset = range.offset + range.length;
+      }
+    } else if line.starts_with('#') {
+      continue;
+    } else if let Some(d) = duration.take() {
+      segments.push(MediaSegment {
+        url: resolve_url(base_url, line),
+        duration: d,
+        byte_range: byte_range.take(),
+      });
+    }
+  }
+
+  MediaPlaylist {
+    segments,
+    init,
+    target_duration,
+    media_sequence,
+    ended,
+  }
+}
+
+pub struct Variant {
+  pub url: String,
+  pub bandwidth: u64,
+  pub resolution: Option<String>,
+  pub audio: Option<String>,
+  pub subtitles: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RenditionType {
+  Audio,
+  Subtitles,
+  Other,
+}
+
+pub struct Rendition {
+  pub kind: RenditionType,
+  pub group_id: String,
+  pub language: Option<String>,
+  pub name: String,
+  pub url: Option<String>,
+  pub default: bool,
+}
+
+impl Rendition {
+  // language tag if present, otherwise the display name
+  pub fn label(&self) -> String {
+    self.language.clone().unwrap_or_else(|| self.name.clone())
+  }
+
+  pub fn matches(&self, wanted: &str) -> bool {
+    wanted.eq_ignore_ascii_case("all")
+      || wanted.eq_ignore_ascii_case(&self.name)
+      || self
+        .language
+        .as_ref()
+        .is_some_and(|l| wanted.eq_ignore_ascii_case(l))
+  }
+}
+
+pub struct MasterPlaylist {
+  pub variants: Vec<Variant>,
+  pub renditions: Vec<Rendition>,
+}
+
+impl MasterPlaylist {
+  pub fn best_variant(&self) -> Option<&Variant> {
+    self.variants.iter().max_by_key(|v| v.bandwidth)
+  }
+
+  // renditions of `kind` that can be fetched separately for `variant`
+  pub fn renditions_for(
+    &self,
+    variant: &Variant,
+    kind: RenditionType,
+  ) -> Vec<&Rendition> {
+    let group = match kind {
+      RenditionType::Audio => &variant.audio,
+      RenditionType::Subtitles => &variant.subtitles,
+      RenditionType::Other => &None,
+    };
+
+    self
+      .renditions
+      .iter()
+      .filter(|r| r.kind == kind && r.url.is_some())
+      .filter(|r| group.as_ref().is_none_or(|g| g == &r.group_id))
+      .collect()
+  }
+}
+
+pub fn is_master_playlist(content: &str) -> bool {
+  content
+    .lines()
+    .any(|line| line.trim().starts_with(HLS_STREAM_INF_TAG))
+}
+
+pub fn parse_master_playlist(content: &str, base_url: &str) -> MasterPlaylist {
+  let mut variants = Vec::new();
+  let mut renditions = Vec::new();
+  let mut stream_inf: Option<HashMap<String, String>> = None;
+
+  for line in content.lines().map(|l| l.trim()) {
+    if line.is_empty() {
+      continue;
+    }
+
+    if let Some(value) = tag_value(line, HLS_STREAM_INF_TAG) {
+      stream_inf = Some(parse_attributes(value));
+    } else if let Some(value) = tag_value(line, HLS_MEDIA_TAG) {
+      let attributes = parse_attributes(value);
+      let kind = match attributes.get("TYPE").map(|t| t.as_str()) {
+        Some("AUDIO") => RenditionType::Audio,
+        Some("SUBTITLES") => RenditionType::Subtitles,
+        _ => RenditionType::Other,
+      };
+
+      renditions.push(Rendition {
+        kind,
+        group_id: attributes.get("GROUP-ID").cloned().unwrap_or_default(),
+        language: attributes.get("LANGUAGE").cloned(),
+        name: attributes.get("NAME").cloned().unwrap_or_default(),
+        url: attributes.get("URI").map(|uri| resolve_url(base_url, uri)),
+        default: attributes.get("DEFAULT").is_some_and(|d| d == "YES"),
+      });
+    } else if line.starts_with('#') {
+      continue;
+    } else if let Some(attributes) = stream_inf.take() {
+      variants.push(Variant {
+        url: resolve_url(base_url, line),
+        bandwidth: attributes
+          .get("BANDWIDTH")
+          .and_then(|b| b.parse::<u64>().ok())
+          .unwrap_or(0),
+        resolution: attributes.get("RESOLUTION").cloned(),
+        audio: attributes.get("AUDIO").cloned(),
+        subtitles: attributes.get("SUBTITLES").cloned(),
+      });
+    }
+  }
+
+  MasterPlaylist {
+    variants,
+    renditions,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const BASE: &str = "https://cdn.example/hls/ep-1/index.m3u8";
+
+  fn range(range: &Option<ByteRange>) -> Option<(u64, u64)> {
+    range.as_ref().map(|r| (r.length, r.offset))
+  }
+
+  #[test]
+  fn map_with_byterange() {
+    let playlist = parse_media_playlist(
+      "#EXTM3U\n\
+       #EXT-X-MAP:URI=\"main.mp4\",BYTERANGE=\"720@0\"\n\
+       #EXTINF:4.0,\n\
+       #EXT-X-BYTERANGE:1000@720\n\
+       main.mp4\n\
+       #EXT-X-ENDLIST\n",
+      BASE,
+    );
+
+    let init = playlist.init.as_ref().unwrap();
+    assert_eq!(init.url, "https://cdn.example/hls/ep-1/main.mp4");
+    assert_eq!(range(&init.byte_range), Some((720, 0)));
+    assert_eq!(
+      init.byte_range.as_ref().unwrap().header_value(),
+      "bytes=0-719"
+    );
+    assert_eq!(range(&playlist.segments[0].byte_range), Some((1000, 720)));
+    assert_eq!(playlist.extension(), "mp4");
+  }
+
+  #[test]
+  fn chained_byteranges() {
+    let playlist = parse_media_playlist(
+      "#EXTM3U\n\
+       #EXTINF:4.0,\n\
+       #EXT-X-BYTERANGE:1000@188\n\
+       all.ts\n\
+       #EXTINF:4.0,\n\
+       #EXT-X-BYTERANGE:1500\n\
+       all.ts\n\
+       #EXTINF:2.5,\n\
+       #EXT-X-BYTERANGE:500\n\
+       all.ts\n",
+      BASE,
+    );
+
+    let ranges: Vec<_> = playlist
+      .segments
+      .iter()
+      .map(|s| range(&s.byte_range))
+      .collect();
+    assert_eq!(
+      ranges,
+      [Some((1000, 188)), Some((1500, 1188)), Some((500, 2688))]
+    );
+    assert_eq!(playlist.duration(), 10.5);
+    assert!(!playlist.ended);
+    assert_eq!(playlist.extension(), "ts");
+  }
+
+  #[test]
+  fn empty_byterange_is_ignored() {
+    let playlist = parse_media_playlist(
+      "#EXTM3U\n\
+       #EXTINF:4.0,\n\
+       #EXT-X-BYTERANGE:0@188\n\
+       all.ts\n\
+       #EXTINF:4.0,\n\
+       #EXT-X-BYTERANGE:1000\n\
+       all.ts\n",
+      BASE,
+    );
+
+    assert!(playlist.segments[0].byte_range.is_none());
+    assert_eq!(range(&playlist.segments[1].byte_range), Some((1000, 0)));
+  }
+
+  #[test]
+  fn quoted_attributes_with_commas() {
+    let attributes = parse_attributes(
+      "TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"Korean, dubbed\",\
+       CODECS=\"avc1.640028,mp4a.40.2\",DEFAULT=YES",
+    );
+
+    assert_eq!(attributes["NAME"], "Korean, dubbed");
+    assert_eq!(attributes["CODECS"], "avc1.640028,mp4a.40.2");
+    assert_eq!(attributes["GROUP-ID"], "aud");
+    assert_eq!(attributes["DEFAULT"], "YES");
+    assert_eq!(attributes.len(), 5);
+  }
+
+  #[test]
+  fn m4s_segments_are_mp4() {
+    let playlist = parse_media_playlist(
+      "#EXTM3U\n\
+       #EXTINF:4.0,\n\
+       seg0.m4s?token=abc\n\
+       #EXTINF:4.0,\n\
+       seg1.m4s?token=abc\n",
+      BASE,
+    );
+
+    assert!(playlist.init.is_none());
+    assert!(playlist.is_fmp4());
+    assert_eq!(playlist.extension(), "mp4");
+    assert_eq!(
+      playlist.segments[1].url,
+      "https://cdn.example/hls/ep-1/seg1.m4s?token=abc"
+    );
+  }
+}
@@ -1,13 +1,425 @@
-use reqwest::{header, Client};
+use std::{
+  collections::BTreeMap,
+  error::Error,
+  fmt, fs,
+  net::SocketAddr,
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, RwLock,
+  },
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use crate::api;
+use hyper::client::connect::dns::Name;
 
-pub fn create_http_client() -> Client {
+use log::{debug, info, warn};
+use reqwest::{
+  cookie::Jar,
+  dns::{Addrs, Resolve, Resolving},
+  header, redirect, Client, Proxy, RequestBuilder, Response, StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  api,
+  bandwidth::{self, RateWindow},
+  ratelimit::{self, Rates},
+  session, AsyncResult,
+};
+
+const MAX_REDIRECTS: usize = 10;
+
+// reqwest has no read timeout of its own, bodies are read through read_body
+static READ_TIMEOUT: RwLock<Option<Duration>> = RwLock::new(None);
+// bytes per second a body has to keep up over a stall window
+static MIN_SPEED: RwLock<Option<u64>> = RwLock::new(None);
+pub const DEFAULT_MIN_SPEED: u64 = 4 * 1024;
+// user agent and extra headers of the client, for handing streams to other
+// tools
+static SENT_HEADERS: RwLock<Vec<(String, String)>> = RwLock::new(Vec::new());
+// user_agents to take turns with, empty when one user agent is used
+static USER_AGENTS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+static NEXT_USER_AGENT: AtomicUsize = AtomicUsize::new(0);
+const STALL_WINDOW: Duration = Duration::from_secs(10);
+const STALL_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum IpVersion {
+  #[serde(rename = "4")]
+  V4,
+  #[serde(rename = "6")]
+  V6,
+}
+
+// Everything about how requests are sent. Read from the config file, flags
+// override single fields. Timeouts are in seconds.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+  pub user_agent: Option<String>,
+  // requests take turns with these when user_agent is not set
+  pub user_agents: Vec<String>,
+  // Netscape cookies.txt exported from a browser
+  pub cookies: Option<String>,
+  // http://, https://, socks5:// or socks5h:// (dns through the proxy)
+  pub proxy: Option<String>,
+  pub connect_timeout: Option<u64>,
+  // longest wait for the next chunk of a response body
+  pub read_timeout: Option<u64>,
+  // slowest a body may get before the request is dropped as stalled, "0"
+  // turns the check off
+  pub min_speed: Option<String>,
+  // whole request, body included
+  pub timeout: Option<u64>,
+  pub headers: BTreeMap<String, String>,
+  pub ip_version: Option<IpVersion>,
+  // requests per second to the site and to each video host, 0 for no limit
+  pub page_rate: Option<f64>,
+  pub segment_rate: Option<f64>,
+  // combined cap of all downloads, e.g. "5M", and times of day that differ
+  pub limit_rate: Option<String>,
+  pub limit_schedule: Vec<RateWindow>,
+}
+
+impl HttpConfig {
+  // The fixed user agent, or the first of the pool, or the built in one
+  fn default_user_agent(&self) -> &str {
+    match (&self.user_agent, self.user_agents.first()) {
+      (Some(user_agent), _) | (None, Some(user_agent)) => user_agent,
+      (None, None) => api::USER_AGENT,
+    }
+  }
+}
+
+// The next user agent of the pool, round robin
+fn next_user_agent() -> Option<String> {
+  let pool = USER_AGENTS.read().unwrap();
+  if pool.is_empty() {
+    return None;
+  }
+  let next = NEXT_USER_AGENT.fetch_add(1, Ordering::Relaxed);
+  Some(pool[next % pool.len()].clone())
+}
+
+// Resolves hosts with the addresses of one family first. The connector tries
+// the first family and falls back to the other after a short wait, so this
+// is a preference rather than a restriction.
+struct PreferFamily(IpVersion);
+
+impl Resolve for PreferFamily {
+  fn resolve(&self, name: Name) -> Resolving {
+    let prefer_v6 = matches!(self.0, IpVersion::V6);
+    Box::pin(async move {
+      let mut addrs: Vec<SocketAddr> =
+        tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+      // stable, keeps the system's order within a family
+      addrs.sort_by_key(|addr| addr.is_ipv6() != prefer_v6);
+      Ok(Box::new(addrs.into_iter()) as Addrs)
+    })
+  }
+}
+
+// Parses a curl style "Name: value" header
+pub fn parse_header(header: &str) -> Option<(String, String)> {
+  let (name, value) = header.split_once(':')?;
+  let name = name.trim();
+  if name.is_empty() {
+    return None;
+  }
+  Some((name.to_string(), value.trim().to_string()))
+}
+
+// Sends `req` once the rate limiter allows it. Every request should go
+// through here so 429s and resets slow down the host. Only the configured
+// timeouts apply.
+pub async fn send(
+  client: &Client,
+  req: RequestBuilder,
+) -> AsyncResult<Response> {
+  send_watched(client, req, false).await
+}
+
+// `send` for segments: headers taking a whole stall window count as a stall,
+// like a crawling body in read_segment. Segments are retried, pages are not.
+pub async fn send_segment(
+  client: &Client,
+  req: RequestBuilder,
+) -> AsyncResult<Response> {
+  let watched = MIN_SPEED.read().unwrap().is_some();
+  send_watched(client, req, watched).await
+}
+
+async fn send_watched(
+  client: &Client,
+  req: RequestBuilder,
+  watched: bool,
+) -> AsyncResult<Response> {
+  let mut request = req.build()?;
+  if let Some(user_agent) = next_user_agent() {
+    request
+      .headers_mut()
+      .insert("User-Agent", header::HeaderValue::from_str(&user_agent)?);
+  }
+  let url = request.url().clone();
+  ratelimit::acquire(&url).await;
+
+  let execute = client.execute(request);
+  let result = if watched {
+    match tokio::time::timeout(STALL_WINDOW, execute).await {
+      Ok(result) => result,
+      Err(_) => return Err(Stalled { url, speed: 0 }.into()),
+    }
+  } else {
+    execute.await
+  };
+  ratelimit::record(&url, &result);
+  Ok(result?)
+}
+
+// No body data arrived within the read timeout
+#[derive(Debug)]
+pub struct ReadTimeout {
+  url: Url,
+  limit: Duration,
+}
+
+impl fmt::Display for ReadTimeout {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "no data from {} for {}s", self.url, self.limit.as_secs())
+  }
+}
+
+impl Error for ReadTimeout {}
+
+// The body crawled below the minimum speed for a whole stall window
+#[derive(Debug)]
+pub struct Stalled {
+  url: Url,
+  // bytes per second over the window
+  speed: u64,
+}
+
+impl fmt::Display for Stalled {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{} stalled at {} B/s", self.url, self.speed)
+  }
+}
+
+impl Error for Stalled {}
+
+// Reads a response body and counts every chunk against the bandwidth limit.
+// Fails when no data arrives for the read timeout.
+pub async fn read_body(res: Response) -> AsyncResult<Vec<u8>> {
+  read_watched(res, None).await
+}
+
+// `read_body` for segments, also failing when the body stays below the
+// minimum speed for a stall window
+pub async fn read_segment(res: Response) -> AsyncResult<Vec<u8>> {
+  let min_speed = *MIN_SPEED.read().unwrap();
+  read_watched(res, min_speed).await
+}
+
+async fn read_watched(
+  mut res: Response,
+  min_speed: Option<u64>,
+) -> AsyncResult<Vec<u8>> {
+  let read_timeout = *READ_TIMEOUT.read().unwrap();
+  let url = res.url().clone();
+  let mut body = Vec::new();
+  let mut last_data = Instant::now();
+  let mut window_start = Instant::now();
+  let mut window_bytes = 0;
+
+  loop {
+    let polled = if read_timeout.is_none() && min_speed.is_none() {
+      Some(res.chunk().await?)
+    } else {
+      tokio::time::timeout(STALL_POLL, res.chunk())
+        .await
+        .ok()
+        .transpose()?
+    };
+
+    match polled {
+      Some(None) => return Ok(body),
+      Some(Some(chunk)) => {
+        window_bytes += chunk.len();
+        body.extend_from_slice(&chunk);
+        // waiting on the bandwidth limit is not the server being slow
+        let held = Instant::now();
+        bandwidth::consume(chunk.len()).await;
+        window_start += held.elapsed();
+        last_data = Instant::now();
+      }
+      None => {}
+    }
+
+    if let Some(limit) = read_timeout {
+      if last_data.elapsed() >= limit {
+        return Err(ReadTimeout { url, limit }.into());
+      }
+    }
+    if let Some(min_speed) = min_speed {
+      let elapsed = window_start.elapsed();
+      if elapsed >= STALL_WINDOW {
+        let speed = (window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+        if speed < min_speed {
+          return Err(Stalled { url, speed }.into());
+        }
+        window_start = Instant::now();
+        window_bytes = 0;
+      }
+    }
+  }
+}
+
+pub fn sent_headers() -> Vec<(String, String)> {
+  SENT_HEADERS.read().unwrap().clone()
+}
+
+// The user agent of this run, the default one before the client is built
+pub fn user_agent() -> String {
+  sent_headers()
+    .into_iter()
+    .find(|(name, _)| name == "User-Agent")
+    .map(|(_, value)| value)
+    .unwrap_or_else(|| api::USER_AGENT.to_string())
+}
+
+pub async fn read_text(res: Response) -> AsyncResult<String> {
+  let body = read_body(res).await?;
+  Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+// Loads a Netscape format cookies.txt into `jar`, returning how many cookies
+// were added. Expired cookies are skipped.
+pub fn load_cookies(jar: &Jar, path: &str) -> AsyncResult<usize> {
+  let content = fs::read_to_string(path)?;
+  let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+  let mut count = 0;
+
+  for line in content.lines() {
+    // curl marks HttpOnly cookies with a prefix on the domain
+    let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+    if line.trim().is_empty() || line.starts_with('#') {
+      continue;
+    }
+
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [domain, _, cookie_path, secure, expires, name, value] = fields[..]
+    else {
+      warn!("skipping malformed cookie line in {}", path);
+      continue;
+    };
+    let expires = expires.parse::<u64>().unwrap_or(0);
+    if expires != 0 && expires < now {
+      continue;
+    }
+
+    let host = domain.trim_start_matches('.');
+    let Ok(url) = Url::parse(&format!("https://{}{}", host, cookie_path))
+    else {
+      continue;
+    };
+    let mut cookie =
+      format!("{}={}; Domain={}; Path={}", name, value, host, cookie_path);
+    if secure.eq_ignore_ascii_case("TRUE") {
+      cookie.push_str("; Secure");
+    }
+    jar.add_cookie_str(&cookie, &url);
+    count += 1;
+  }
+
+  Ok(count)
+}
+
+pub fn create_http_client(options: &HttpConfig) -> AsyncResult<Client> {
+  let user_agent = options.default_user_agent();
+  debug!("user agent: {}", user_agent);
+  // a fixed user agent wins over the pool
+  *USER_AGENTS.write().unwrap() = match options.user_agent {
+    Some(_) => Vec::new(),
+    None => options.user_agents.clone(),
+  };
   let mut headers = header::HeaderMap::new();
-  headers.insert(
-    "User-Agent",
-    header::HeaderValue::from_static(api::USER_AGENT),
-  );
+  headers.insert("User-Agent", header::HeaderValue::from_str(user_agent)?);
+  for (name, value) in &options.headers {
+    headers.insert(
+      header::HeaderName::from_bytes(name.as_bytes())?,
+      header::HeaderValue::from_str(value)?,
+    );
+  }
+  *SENT_HEADERS.write().unwrap() =
+    std::iter::once(("User-Agent".to_string(), user_agent.to_string()))
+      .chain(options.headers.clone())
+      .collect();
+
+  // cookies set by the site (or imported) live for the whole run
+  let jar = Arc::new(Jar::default());
+  if let Some(path) = &options.cookies {
+    let count = load_cookies(&jar, path)?;
+    info!("loaded {} cookies from {}", count, path);
+    if options.user_agent.is_none() {
+      warn!("clearance cookies usually need --user-agent of the same browser");
+    }
+  }
+  session::restore(jar.clone());
+
+  // same as the default policy, but notes when the site moves for good
+  let policy = redirect::Policy::custom(|attempt| {
+    if matches!(
+      attempt.status(),
+      StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT
+    ) {
+      if let Some(previous) = attempt.previous().last() {
+        let from = previous.origin().ascii_serialization();
+        let to = attempt.url().origin().ascii_serialization();
+        if from != to {
+          api::record_permanent_redirect(&from, &to);
+        }
+      }
+    }
+
+    if attempt.previous().len() > MAX_REDIRECTS {
+      attempt.error("too many redirects")
+    } else {
+      attempt.follow()
+    }
+  });
+
+  let mut builder = Client::builder()
+    .default_headers(headers)
+    .cookie_provider(jar)
+    .redirect(policy);
+  if let Some(proxy) = &options.proxy {
+    builder = builder.proxy(Proxy::all(proxy)?);
+    info!("using proxy {}", proxy);
+  }
+  if let Some(secs) = options.connect_timeout {
+    builder = builder.connect_timeout(Duration::from_secs(secs));
+  }
+  if let Some(secs) = options.timeout {
+    builder = builder.timeout(Duration::from_secs(secs));
+  }
+  if let Some(version) = options.ip_version {
+    builder = builder.dns_resolver(Arc::new(PreferFamily(version)));
+  }
+  *READ_TIMEOUT.write().unwrap() =
+    options.read_timeout.map(Duration::from_secs);
+  ratelimit::configure(Rates {
+    pages: options.page_rate.unwrap_or(ratelimit::DEFAULT_PAGE_RATE),
+    segments: options
+      .segment_rate
+      .unwrap_or(ratelimit::DEFAULT_SEGMENT_RATE),
+  });
+  *MIN_SPEED.write().unwrap() = match &options.min_speed {
+    Some(speed) => bandwidth::parse_rate(speed)
+      .ok_or(format!("invalid min speed {}", speed))?,
+    None => Some(DEFAULT_MIN_SPEED),
+  };
+  bandwidth::configure(options.limit_rate.as_deref(), &options.limit_schedule)?;
 
-  Client::builder().default_headers(headers).build().unwrap()
+  let client = builder.build()?;
+  Ok(client)
 }
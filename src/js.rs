@@ -0,0 +1,192 @@
+use boa_engine::{js_string, Context, JsValue, Source};
+use log::debug;
+use regex::Regex;
+use scraper::{Html, Selector};
+
+// keeps hostile or broken scripts from spinning forever
+const LOOP_ITERATION_LIMIT: u64 = 10_000_000;
+const RECURSION_LIMIT: usize = 512;
+
+// Stubbed browser globals. Anything not modelled resolves to `__any`, a
+// callable proxy that swallows property access and calls, runs callbacks
+// handed to it and records video urls passed to player setup calls. `eval`
+// is wrapped so unpacked code can be inspected afterwards.
+const PRELUDE: &str = r#"
+var window = globalThis, self = globalThis, top = globalThis,
+  parent = globalThis;
+var __evaluated = [], __urls = [];
+// jwplayer nests urls as deep as playlist[0].sources[0].file
+var __record = function (value, depth) {
+  if (depth > 6 || value === null || value === undefined) return;
+  if (typeof value === 'string') {
+    if (/\.m3u8/.test(value)) __urls.push(value);
+  } else if (typeof value === 'object') {
+    for (var key in value) {
+      try { __record(value[key], depth + 1); } catch (e) {}
+    }
+  }
+};
+var __any = new Proxy(function () {}, {
+  get: function (target, key) {
+    if (key === Symbol.toPrimitive) return function () { return ''; };
+    if (key === 'length') return 0;
+    return __any;
+  },
+  set: function () { return true; },
+  apply: function (target, that, args) {
+    for (var i = 0; i < args.length; i++) {
+      if (typeof args[i] === 'function') {
+        try { args[i].call(__any, __any); } catch (e) {}
+      } else {
+        __record(args[i], 0);
+      }
+    }
+    return __any;
+  },
+  construct: function () { return __any; }
+});
+var location = {
+  href: '', hostname: '', host: '', pathname: '/', protocol: 'https:',
+  search: '', hash: '', reload: function () {}, replace: function () {}
+};
+var navigator = { userAgent: __USER_AGENT__, language: 'ko-KR' };
+var document = new Proxy({
+  cookie: '', referrer: '', location: location, readyState: 'complete',
+  querySelectorAll: function () { return []; },
+  getElementsByTagName: function () { return []; },
+  write: function (html) { __record(html, 0); }
+}, {
+  get: function (target, key) { return key in target ? target[key] : __any; }
+});
+var console = {
+  log: function () {}, warn: function () {}, error: function () {},
+  info: function () {}, debug: function () {}
+};
+var setTimeout = function (fn) {
+  if (typeof fn === 'function') { try { fn(); } catch (e) {} }
+  return 0;
+};
+var setInterval = function () { return 0; };
+var clearTimeout = function () {}, clearInterval = function () {};
+var $ = __any, jQuery = __any, jwplayer = __any, videojs = __any,
+  Hls = __any, localStorage = __any, sessionStorage = __any;
+var __chars =
+  'ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/';
+var atob = function (input) {
+  var str = String(input).replace(/[=\s]+$/, ''), out = '';
+  for (var bc = 0, bs = 0, buffer, i = 0; (buffer = str.charAt(i++));
+    ~buffer && (bs = bc % 4 ? bs * 64 + buffer : buffer, bc++ % 4)
+      ? (out += String.fromCharCode(255 & bs >> (-2 * bc & 6))) : 0) {
+    buffer = __chars.indexOf(buffer);
+  }
+  return out;
+};
+var btoa = function (input) {
+  var str = String(input), out = '';
+  for (var block, code, idx = 0, map = __chars;
+    str.charAt(idx | 0) || (map = '=', idx % 1);
+    out += map.charAt(63 & block >> 8 - idx % 1 * 8)) {
+    code = str.charCodeAt(idx += 3 / 4);
+    block = block << 8 | code;
+  }
+  return out;
+};
+(function () {
+  var original = eval;
+  globalThis.eval = function (code) {
+    __evaluated.push(String(code));
+    return original(code);
+  };
+})();
+"#;
+
+pub enum PlayerScriptResult {
+  AlData(String),
+  VideoUrl(String),
+}
+
+// Runs the inline scripts of a player page in a sandbox and looks for
+// `_aldata`, first as a global, then in any code passed through eval, and
+// finally falls back to a video url handed to a player setup call.
+// `navigator.userAgent` is `user_agent`, the one the requests are sent with.
+pub fn evaluate_player_page(
+  html: &str,
+  aldata_pattern: &str,
+  user_agent: &str,
+) -> Result<PlayerScriptResult, String> {
+  let document = Html::parse_document(html);
+  let script_selector = Selector::parse("script:not([src])").unwrap();
+  let scripts: Vec<String> = document
+    .select(&script_selector)
+    .map(|script| script.text().collect::<String>())
+    .collect();
+  if scripts.is_empty() {
+    return Err("no inline scripts".to_string());
+  }
+
+  let mut context = Context::default();
+  context
+    .runtime_limits_mut()
+    .set_loop_iteration_limit(LOOP_ITERATION_LIMIT);
+  context
+    .runtime_limits_mut()
+    .set_recursion_limit(RECURSION_LIMIT);
+
+  // a json string is a valid js string literal
+  let user_agent = serde_json::to_string(user_agent).unwrap();
+  let prelude = PRELUDE.replace("__USER_AGENT__", &user_agent);
+  context
+    .eval(Source::from_bytes(&prelude))
+    .map_err(|e| format!("prelude: {}", e))?;
+
+  for (index, script) in scripts.iter().enumerate() {
+    if let Err(e) = context.eval(Source::from_bytes(script)) {
+      // later scripts may still define what we need
+      debug!("script #{} threw: {}", index, e);
+    }
+  }
+
+  if let Some(aldata) = global_string(&mut context, "_aldata") {
+    return Ok(PlayerScriptResult::AlData(aldata));
+  }
+
+  let aldata_re = Regex::new(aldata_pattern).unwrap();
+  for code in global_strings(&mut context, "__evaluated") {
+    if let Some(caps) = aldata_re.captures(&code) {
+      return Ok(PlayerScriptResult::AlData(caps[1].to_string()));
+    }
+  }
+
+  match global_strings(&mut context, "__urls").into_iter().next() {
+    Some(url) => Ok(PlayerScriptResult::VideoUrl(url)),
+    None => Err("scripts did not produce _aldata or a video url".to_string()),
+  }
+}
+
+fn global_value(context: &mut Context, name: &str) -> Option<JsValue> {
+  let global = context.global_object();
+  global.get(js_string!(name), context).ok()
+}
+
+fn global_string(context: &mut Context, name: &str) -> Option<String> {
+  let value = global_value(context, name)?;
+  value.as_string()?.to_std_string().ok()
+}
+
+fn global_strings(context: &mut Context, name: &str) -> Vec<String> {
+  let Some(array) =
+    global_value(context, name).and_then(|v| v.as_object().cloned())
+  else {
+    return vec![];
+  };
+
+  let length = array
+    .get(js_string!("length"), context)
+    .ok()
+    .and_then(|l| l.as_number())
+    .unwrap_or(0.0) as u32;
+  (0..length)
+    .filter_map(|index| array.get(index, context).ok())
+    .filter_map(|value| value.as_string()?.to_std_string().ok())
+    .collect()
+}
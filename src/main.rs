@@ -1,16 +1,40 @@
-use std::{env, error::Error, fs};
+use std::{
+  env,
+  error::Error,
+  fs, process,
+  time::{Duration, Instant},
+};
 
 use env_logger::Env;
-use http::create_http_client;
-use log::error;
+use http::{create_http_client, HttpConfig};
+use log::{error, info, warn};
 use regex::Regex;
+use reqwest::Client;
+use tokio::io::AsyncWrite;
 
+pub mod aldata;
 pub mod api;
+pub mod aria2;
+pub mod bandwidth;
+pub mod checksum;
 pub mod cli;
+pub mod concurrency;
+pub mod config;
+pub mod downloader;
+pub mod export;
+pub mod hls;
 pub mod http;
+#[cfg(feature = "js")]
+pub mod js;
+pub mod play;
+pub mod preflight;
+pub mod ratelimit;
+pub mod repair;
+pub mod session;
+pub mod verify;
 pub mod video;
 
-use cli::{parse_args, print_help, CommandType};
+use cli::{parse_args, print_help, CommandArgs, CommandType};
 
 pub type AsyncResult<T> = Result<T, Box<dyn Error>>;
 
@@ -34,15 +58,254 @@ impl FileName for String {
   }
 }
 
+// --host beats $ANILIFE_HOST beats the config file, mirrors from all three
+// are tried after it in that order
+fn configure_hosts(args: &CommandArgs, config: &config::Config) {
+  let host = args
+    .host
+    .clone()
+    .or_else(|| env::var(config::HOST_ENV).ok())
+    .or_else(|| config.host.clone())
+    .unwrap_or_else(|| api::DEFAULT_HOST.to_string());
+
+  let env_mirrors = env::var(config::MIRRORS_ENV).unwrap_or_default();
+  let mirrors = args
+    .mirrors
+    .iter()
+    .cloned()
+    .chain(env_mirrors.split(',').map(|m| m.to_string()))
+    .chain(config.mirrors.iter().cloned());
+
+  api::set_hosts(std::iter::once(host).chain(mirrors).collect());
+}
+
+// Flags override the config file field by field, headers are added to it
+fn http_config(
+  args: &CommandArgs,
+  config: &config::Config,
+) -> AsyncResult<HttpConfig> {
+  let mut http = config.http.clone();
+  if let Some(path) = &args.user_agents {
+    http.user_agents = fs::read_to_string(path)?
+      .lines()
+      .map(|line| line.trim().to_string())
+      .filter(|line| !line.is_empty() && !line.starts_with('#'))
+      .collect();
+  }
+  if args.user_agent.is_some() {
+    http.user_agent = args.user_agent.clone();
+  }
+  if args.cookies.is_some() {
+    http.cookies = args.cookies.clone();
+  }
+  if args.proxy.is_some() {
+    http.proxy = args.proxy.clone();
+  }
+  http.connect_timeout = args.connect_timeout.or(http.connect_timeout);
+  http.read_timeout = args.read_timeout.or(http.read_timeout);
+  if args.min_speed.is_some() {
+    http.min_speed = args.min_speed.clone();
+  }
+  http.timeout = args.timeout.or(http.timeout);
+  http.headers.extend(args.headers.iter().cloned());
+  http.ip_version = args.ip_version.or(http.ip_version);
+  http.page_rate = args.page_rate.or(http.page_rate);
+  http.segment_rate = args.segment_rate.or(http.segment_rate);
+  // a rate on the command line holds for the whole run
+  if args.limit_rate.is_some() {
+    http.limit_rate = args.limit_rate.clone();
+    http.limit_schedule.clear();
+  }
+  Ok(http)
+}
+
+fn download_options(args: &CommandArgs) -> api::DownloadOptions {
+  api::DownloadOptions {
+    max_concurrent: args.max_concurrent,
+    adaptive: args.adaptive,
+    hedge: args.hedge,
+    audio: args.audio.clone(),
+    subs: args.subs.clone(),
+    mux: args.mux,
+    record: args.record,
+    record_timeout: args.record_timeout.map(Duration::from_secs),
+    clip: match (args.from, args.to) {
+      (None, None) => None,
+      (from, to) => Some((from.unwrap_or(0.0), to.unwrap_or(f64::INFINITY))),
+    },
+    trim: args.trim,
+    // the segment map carries the per segment hashes
+    segment_map: args.segment_map || args.checksums,
+    checksums: args.checksums,
+    aria2: args.aria2.as_ref().map(|url| aria2::Aria2Options {
+      url: url.clone(),
+      secret: args.aria2_secret.clone(),
+    }),
+    resolve: resolve_options(args),
+  }
+}
+
+fn resolve_options(args: &CommandArgs) -> api::ResolveOptions {
+  api::ResolveOptions {
+    quality: args.quality.clone(),
+    debug_dump: args.debug_dump.clone(),
+  }
+}
+
+// planned streams older than this are resolved again before downloading
+const PLAN_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+// Downloads one planned episode, resolving it again when the plan is old or
+// the download fails
+async fn download_plan(
+  client: &Client,
+  plan: &preflight::Planned,
+  stream: &api::EpisodeStream,
+  options: &api::DownloadOptions,
+) -> AsyncResult<()> {
+  let resolve = || {
+    api::get_episode_hls(
+      client,
+      &stream.episode_url,
+      &stream.referer,
+      &options.resolve,
+    )
+  };
+  // links resolved long ago may have expired by the time a long series
+  // gets to later episodes
+  let fresh = if plan.resolved.elapsed() > PLAN_MAX_AGE {
+    info!(
+      "episode {} was resolved a while ago, resolving again",
+      plan.num
+    );
+    Some(resolve().await?)
+  } else {
+    None
+  };
+  let Err(e) = api::download_episode(
+    client,
+    fresh.as_ref().unwrap_or(stream),
+    &plan.output,
+    options,
+  )
+  .await
+  else {
+    return Ok(());
+  };
+  if fresh.is_some() {
+    return Err(e);
+  }
+  warn!("episode {} failed: {}, resolving again", plan.num, e);
+  let stream = resolve().await?;
+  api::download_episode(client, &stream, &plan.output, options).await?;
+  Ok(())
+}
+
+// Resolves every episode and estimates its size before downloading any, so
+// a series that does not fit fails early. --dry-run stops after printing the
+// plan. Episodes that do not resolve or download are skipped and reported
+// at the end.
+async fn download_planned(
+  client: &Client,
+  anime: &api::LifeAnime,
+  episodes: &[&api::LifeEpisodeInfo],
+  path: &str,
+  options: &api::DownloadOptions,
+  args: &CommandArgs,
+) -> AsyncResult<()> {
+  let mut plans = Vec::new();
+  for episode in episodes {
+    let stream = api::get_episode_hls(
+      client,
+      &episode.url,
+      &anime.info.url,
+      &options.resolve,
+    )
+    .await;
+    let resolved = Instant::now();
+
+    // a live stream has no size yet
+    let estimate = match &stream {
+      Ok(stream) if !options.record => {
+        match api::estimate_episode(client, stream, options).await {
+          Ok(e) => Some(e),
+          Err(e) => {
+            warn!("no size estimate for episode {}: {}", episode.num, e);
+            None
+          }
+        }
+      }
+      Ok(_) => None,
+      Err(e) => {
+        error!(
+          "unable to get episode hls of episode {}: {}",
+          episode.num, e
+        );
+        None
+      }
+    };
+
+    let filename = format!("{}-{}", episode.num.zero_pad(2), episode.title)
+      .to_string()
+      .sanitize();
+    plans.push(preflight::Planned {
+      num: episode.num.clone(),
+      title: episode.title.clone(),
+      stream,
+      resolved,
+      output: format!("{}/{}", path, filename),
+      estimate,
+    });
+  }
+
+  if args.dry_run {
+    preflight::print_plan(&plans);
+  }
+  if !options.record {
+    preflight::check_space(&plans, options.mux, args.force)?;
+  }
+  if args.dry_run {
+    return Ok(());
+  }
+
+  fs::create_dir_all(path).unwrap();
+  let mut failures = Vec::new();
+  for plan in &plans {
+    let result = match &plan.stream {
+      Ok(stream) => download_plan(client, plan, stream, options).await,
+      Err(e) => {
+        failures.push((&plan.num, format!("not resolved: {}", e)));
+        continue;
+      }
+    };
+    if let Err(e) = result {
+      error!("episode {} failed: {}", plan.num, e);
+      failures.push((&plan.num, e.to_string()));
+    }
+  }
+
+  if failures.is_empty() {
+    return Ok(());
+  }
+  for (num, reason) in &failures {
+    error!("episode {}: {}", num, reason);
+  }
+  Err(format!("{} of {} episodes failed", failures.len(), plans.len()).into())
+}
+
 extern crate log;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
   env_logger::Builder::from_env(Env::default().default_filter_or("info"))
     .init();
-  let client = create_http_client();
   let args = env::args();
-  let command = parse_args(args).unwrap();
+  // the reason was logged already
+  let command = parse_args(args).unwrap_or_else(|_| process::exit(1));
+  let config = config::Config::load();
+  configure_hosts(&command.args, &config);
+  let client = create_http_client(&http_config(&command.args, &config)?)?;
+  let options = download_options(&command.args);
 
   match command.t {
     CommandType::Help => {
@@ -105,11 +368,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
       })
     }
     CommandType::Download => {
-      let anime_id = command.args.anime_id;
-      let episode_nums = command.args.episode_nums;
-      let max_concurrent = command.args.max_concurrent;
+      let anime_id = &command.args.anime_id;
+      let episode_nums = &command.args.episode_nums;
 
-      let anime = match api::get_anime(&client, &anime_id).await {
+      let anime = match api::get_anime(&client, anime_id).await {
         Ok(a) => a,
         Err(e) => {
           error!("Failed to get anime with id {}", anime_id);
@@ -118,44 +380,103 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
       };
       println!("{} {}", anime.episodes[0].url, anime.info.url);
 
+      let mut episodes = Vec::new();
       for episode_num in episode_nums {
-        let episode = match anime
+        match anime
           .episodes
           .iter()
-          .find(|episode| episode.num.eq(&episode_num))
+          .find(|episode| episode.num.eq(episode_num))
         {
-          Some(e) => e,
+          Some(e) => episodes.push(e),
           None => {
             error!("Episode with episode num {} not found", episode_num);
             return Ok(());
           }
         };
-
-        let hls_url =
-          match api::get_episode_hls(&client, &episode.url, &anime.info.url)
-            .await
-          {
-            Ok(h) => h,
-            Err(e) => {
-              error!("unable to get episode hls");
-              return Err(e);
-            }
-          };
-
-        let path = format!("./{}", anime.info.title);
-        let filename =
-          format!("{}-{}.ts", episode.num.zero_pad(2), episode.title)
-            .to_string()
-            .sanitize();
-        fs::create_dir_all(&path).unwrap();
-        let filename = format!("{}/{}", path, filename);
-        api::download_episode(&client, &hls_url, &filename, max_concurrent)
-          .await?;
       }
+
+      let path = format!("./{}", anime.info.title);
+      download_planned(
+        &client,
+        &anime,
+        &episodes,
+        &path,
+        &options,
+        &command.args,
+      )
+      .await?;
     }
     CommandType::DownloadAll => {
+      let anime_id = &command.args.anime_id;
+      let anime = match api::get_anime(&client, anime_id).await {
+        Ok(a) => a,
+        Err(e) => {
+          error!("Failed to get anime with id {}", anime_id);
+          return Err(e);
+        }
+      };
+
+      let episodes: Vec<&api::LifeEpisodeInfo> =
+        anime.episodes.iter().collect();
+      let path = format!("./{}", &anime.info.title.sanitize());
+      download_planned(
+        &client,
+        &anime,
+        &episodes,
+        &path,
+        &options,
+        &command.args,
+      )
+      .await?;
+    }
+    CommandType::Info => {
+      let anime_id = command.args.anime_id;
+      let anime = match api::get_anime(&client, &anime_id).await {
+        Ok(a) => a,
+        Err(e) => {
+          error!("Failed to get anime with id {}", anime_id);
+          return Err(e);
+        }
+      };
+
+      for episode_num in command.args.episode_nums {
+        let Some(episode) = anime
+          .episodes
+          .iter()
+          .find(|episode| episode.num.eq(&episode_num))
+        else {
+          error!("Episode with episode num {} not found", episode_num);
+          continue;
+        };
+
+        match api::get_episode_qualities(
+          &client,
+          &episode.url,
+          &anime.info.url,
+          &options.resolve,
+        )
+        .await
+        {
+          Ok(qualities) => println!(
+            "{:4} | {} | {}",
+            episode.num,
+            episode.title,
+            qualities
+              .iter()
+              // "auto" when the player hands over a playlist directly
+              .map(|q| match q.parse::<u32>() {
+                Ok(_) => format!("{}p", q),
+                Err(_) => q.clone(),
+              })
+              .collect::<Vec<String>>()
+              .join(", ")
+          ),
+          Err(e) => error!("unable to get episode {} info: {}", episode.num, e),
+        }
+      }
+    }
+    CommandType::Resolve => {
       let anime_id = command.args.anime_id;
-      let max_concurrent = command.args.max_concurrent;
       let anime = match api::get_anime(&client, &anime_id).await {
         Ok(a) => a,
         Err(e) => {
@@ -164,29 +485,171 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
       };
 
-      for episode in anime.episodes {
-        let hls_url =
-          match api::get_episode_hls(&client, &episode.url, &anime.info.url)
-            .await
-          {
-            Ok(h) => h,
-            Err(e) => {
-              error!("unable to get episode hls");
-              return Err(e);
-            }
-          };
-
-        let path = format!("./{}", &anime.info.title.sanitize());
-        let filename =
-          format!("{}-{}.ts", episode.num.zero_pad(2), episode.title)
-            .to_string()
-            .sanitize();
-        fs::create_dir_all(&path).unwrap();
-        let filename = format!("{}/{}", path, filename);
-        api::download_episode(&client, &hls_url, &filename, max_concurrent)
-          .await?;
+      let mut streams = Vec::new();
+      for episode_num in command.args.episode_nums {
+        let Some(episode) = anime
+          .episodes
+          .iter()
+          .find(|episode| episode.num.eq(&episode_num))
+        else {
+          error!("Episode with episode num {} not found", episode_num);
+          continue;
+        };
+
+        match api::get_episode_hls(
+          &client,
+          &episode.url,
+          &anime.info.url,
+          &options.resolve,
+        )
+        .await
+        {
+          Ok(stream) => streams.push(export::ExportedStream {
+            filename: format!("{}-{}", episode.num.zero_pad(2), episode.title)
+              .sanitize(),
+            title: format!(
+              "{} - {} {}",
+              anime.info.title, episode.num, episode.title
+            ),
+            url: stream.hls_url,
+          }),
+          Err(e) => error!("unable to resolve episode {}: {}", episode.num, e),
+        }
+      }
+
+      export::print(
+        command.args.format.unwrap_or(export::ExportFormat::Url),
+        &streams,
+        &api::stream_headers(),
+      );
+    }
+    CommandType::Play => {
+      let anime_id = &command.args.anime_id;
+      let anime = match api::get_anime(&client, anime_id).await {
+        Ok(a) => a,
+        Err(e) => {
+          error!("Failed to get anime with id {}", anime_id);
+          return Err(e);
+        }
+      };
+
+      // --player beats the config file
+      let mut player = None;
+      let mut out: Box<dyn AsyncWrite + Unpin> = if command.args.stdout {
+        Box::new(tokio::io::stdout())
+      } else {
+        let player_command = command
+          .args
+          .player
+          .clone()
+          .or(config.player.clone())
+          .unwrap_or_else(|| play::DEFAULT_PLAYER.to_string());
+        let mut child = play::spawn_player(&player_command)?;
+        let stdin = child.stdin.take().ok_or("player has no stdin")?;
+        player = Some(child);
+        Box::new(stdin)
+      };
+
+      for episode_num in &command.args.episode_nums {
+        let Some(episode) = anime
+          .episodes
+          .iter()
+          .find(|episode| episode.num.eq(episode_num))
+        else {
+          error!("Episode with episode num {} not found", episode_num);
+          continue;
+        };
+
+        let stream = api::get_episode_hls(
+          &client,
+          &episode.url,
+          &anime.info.url,
+          &options.resolve,
+        )
+        .await?;
+        info!("playing {} {}", episode.num, episode.title);
+        match play::play_episode(
+          &client,
+          &stream,
+          &options.resolve,
+          options.clip,
+          out.as_mut(),
+        )
+        .await?
+        {
+          play::Played::Finished => {}
+          play::Played::Closed => {
+            info!("player closed, stopping");
+            break;
+          }
+        }
+      }
+
+      // closing stdin lets the player finish what it buffered
+      drop(out);
+      if let Some(mut child) = player {
+        child.wait().await?;
+      }
+    }
+    CommandType::Inspect => {
+      let html = fs::read_to_string(&command.args.filename)?;
+      match api::parse_player_page(&html, |_| {})? {
+        api::PlayerSource::Data(player_data) => {
+          for video_url in &player_data.video_urls {
+            println!("{:>5}p | {}", video_url.quality, video_url.url);
+          }
+        }
+        api::PlayerSource::Hls(url) => println!(" auto | {}", url),
+      }
+    }
+    CommandType::Login => {
+      let username = command.args.username;
+      let password = match env::var(session::PASSWORD_ENV) {
+        Ok(p) => p,
+        Err(_) => {
+          rpassword::prompt_password(format!("password for {}: ", username))?
+        }
+      };
+
+      if let Err(e) = session::login(&client, &username, &password).await {
+        error!("Failed to log in as {}", username);
+        return Err(e);
       }
     }
+    CommandType::Logout => {
+      session::logout(&client).await?;
+    }
+    CommandType::Verify => {
+      let path = std::path::Path::new(&command.args.filename);
+      let (checked, suspects) = verify::verify_library(path)?;
+      for (file, report) in &suspects {
+        println!("{}", file.display());
+        for problem in &report.problems {
+          println!("  {}", problem);
+        }
+      }
+      println!("{} of {} files suspect", suspects.len(), checked);
+      if !suspects.is_empty() {
+        return Err(format!("{} suspect files", suspects.len()).into());
+      }
+    }
+    CommandType::Check => {
+      let path = std::path::Path::new(&command.args.filename);
+      let (checked, failures) = checksum::check_library(path)?;
+      for (file, reason) in &failures {
+        println!("{}: {}", file.display(), reason);
+      }
+      println!("{} of {} files failed", failures.len(), checked);
+      if !failures.is_empty() {
+        return Err(format!("{} files failed", failures.len()).into());
+      }
+    }
+    CommandType::Repair => {
+      let path = std::path::Path::new(&command.args.filename);
+      let replaced =
+        repair::repair_file(&client, path, &options.resolve).await?;
+      println!("{} segments replaced", replaced);
+    }
     CommandType::Concat => {
       video::concat_ts();
     }
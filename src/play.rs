@@ -0,0 +1,151 @@
+use std::{io, process::Stdio};
+
+use futures::{stream, StreamExt};
+use log::{info, warn};
+use reqwest::Client;
+use tokio::{
+  io::{AsyncWrite, AsyncWriteExt},
+  process::{Child, Command},
+};
+
+use crate::{
+  api::{self, EpisodeStream, PlaylistTarget, ResolveOptions, TargetPlaylist},
+  downloader, AsyncResult,
+};
+
+pub const DEFAULT_PLAYER: &str = "mpv -";
+// segments fetched ahead of the one being written, the player's own buffer
+// and the pipe hold the rest
+const READ_AHEAD: usize = 4;
+
+pub enum Played {
+  Finished,
+  // the player quit or the pipe was closed
+  Closed,
+}
+
+// Starts `command` (split on whitespace) with the stream on its stdin
+pub fn spawn_player(command: &str) -> io::Result<Child> {
+  let mut parts = command.split_whitespace();
+  let Some(program) = parts.next() else {
+    return Err(io::Error::other("player command is empty"));
+  };
+  info!("starting {}", command);
+  Command::new(program)
+    .args(parts)
+    .stdin(Stdio::piped())
+    .spawn()
+}
+
+// false once the reading end is gone
+async fn write_out(
+  out: &mut (dyn AsyncWrite + Unpin),
+  bytes: &[u8],
+) -> io::Result<bool> {
+  match out.write_all(bytes).await {
+    Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(false),
+    Err(e) => Err(e),
+    Ok(()) => Ok(true),
+  }
+}
+
+// Writes the episode to `out` in playback order as the segments arrive,
+// keeping at most READ_AHEAD of them in memory. Nothing is staged on disk.
+// Expired links are re-resolved and playback continues where it stopped.
+pub async fn play_episode(
+  client: &Client,
+  stream: &EpisodeStream,
+  resolve: &ResolveOptions,
+  clip: Option<(f64, f64)>,
+  out: &mut (dyn AsyncWrite + Unpin),
+) -> AsyncResult<Played> {
+  // a media playlist is its own variant, re-resolving finds it the same way
+  let target = PlaylistTarget::Variant;
+  let content = api::fetch_playlist(client, &stream.hls_url).await?;
+  let TargetPlaylist { mut playlist, .. } =
+    api::media_playlist_for(client, &stream.hls_url, &content, &target).await?;
+  if let Some((from, to)) = clip {
+    playlist.clip(from, to);
+  }
+  if !playlist.ended {
+    warn!("live playlist, playing the segments listed so far");
+  }
+  info!(
+    "playing {} segments ({:.0}s)",
+    playlist.segments.len(),
+    playlist.duration()
+  );
+
+  if let Some(init) = &playlist.init {
+    let bytes =
+      api::fetch_segment_bytes(client, &init.url, init.byte_range.as_ref())
+        .await?;
+    if !write_out(out, &bytes).await? {
+      return Ok(Played::Closed);
+    }
+  }
+
+  let mut position = 0;
+  let mut refreshes = 0;
+  loop {
+    let mut fetches = stream::iter(playlist.segments.iter().enumerate())
+      .skip(position)
+      .map(|(index, segment)| {
+        downloader::fetch_segment_retrying(client, index, segment)
+      })
+      .buffered(READ_AHEAD);
+
+    let mut failure = None;
+    while let Some(result) = fetches.next().await {
+      let bytes = match result {
+        Ok(bytes) => bytes,
+        Err(e) => {
+          failure = Some(e.to_string());
+          break;
+        }
+      };
+      if !write_out(out, &bytes).await? {
+        return Ok(Played::Closed);
+      }
+      position += 1;
+    }
+    drop(fetches);
+
+    let Some(message) = failure else {
+      break;
+    };
+    if refreshes == api::MAX_REFRESHES {
+      return Err(format!("segment {} failed: {}", position, message).into());
+    }
+    refreshes += 1;
+    warn!(
+      "segment {} failed: {}, re-resolving episode ({}/{})",
+      position,
+      message,
+      refreshes,
+      api::MAX_REFRESHES
+    );
+
+    let resolve = resolve.pinned(&stream.quality);
+    let fresh = api::resolve_playlist(
+      client,
+      &stream.episode_url,
+      &stream.referer,
+      &resolve,
+      &target,
+      clip,
+    )
+    .await?;
+    let durations = playlist.segments.iter().map(|s| s.duration);
+    position = fresh
+      .map_index(durations, position)
+      .unwrap_or(fresh.segments.len());
+    playlist = fresh;
+  }
+
+  match out.flush().await {
+    Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(Played::Closed),
+    Err(e) => Err(e.into()),
+    Ok(()) => Ok(Played::Finished),
+  }
+}
@@ -0,0 +1,114 @@
+use std::{error::Error, path::Path, time::Instant};
+
+use log::{info, warn};
+
+use crate::{
+  api::{EpisodeStream, SizeEstimate},
+  AsyncResult,
+};
+
+// estimates are rough, warn when less than this share of the free space
+// would be left over
+const SPACE_MARGIN: f64 = 0.1;
+
+// One episode of a download, resolved and estimated before anything is
+// written. An episode that did not resolve stays in the plan with the error.
+pub struct Planned {
+  pub num: String,
+  pub title: String,
+  pub stream: Result<EpisodeStream, Box<dyn Error>>,
+  pub resolved: Instant,
+  // without the extension, that depends on the playlist
+  pub output: String,
+  pub estimate: Option<SizeEstimate>,
+}
+
+pub fn format_size(bytes: u64) -> String {
+  let bytes = bytes as f64;
+  if bytes >= 1024.0 * 1024.0 * 1024.0 {
+    format!("{:.1} GiB", bytes / 1024.0 / 1024.0 / 1024.0)
+  } else if bytes >= 1024.0 * 1024.0 {
+    format!("{:.1} MiB", bytes / 1024.0 / 1024.0)
+  } else {
+    format!("{:.1} KiB", bytes / 1024.0)
+  }
+}
+
+pub fn print_plan(plans: &[Planned]) {
+  for plan in plans {
+    let stream = match &plan.stream {
+      Ok(stream) => stream,
+      Err(e) => {
+        println!("{:4} | {} | not resolved: {}", plan.num, plan.title, e);
+        continue;
+      }
+    };
+    let (size, duration, extension) = match &plan.estimate {
+      Some(e) => (
+        format!("~{} ({})", format_size(e.bytes), e.source),
+        format!("{}:{:02}", e.duration as u64 / 60, e.duration as u64 % 60),
+        e.extension,
+      ),
+      None => ("unknown size".to_string(), "?".to_string(), "*"),
+    };
+    println!(
+      "{:4} | {} | {} | {} | {} | {}.{}",
+      plan.num,
+      plan.title,
+      stream.quality.as_deref().unwrap_or("auto"),
+      duration,
+      size,
+      plan.output,
+      extension
+    );
+  }
+}
+
+// Space the plan needs at its peak: every episode, plus the staged segments
+// of the largest one (they sit next to the combined file until it is done),
+// plus its .ts once more while muxing.
+fn space_needed(plans: &[&Planned], mux: bool) -> u64 {
+  let sizes = plans.iter().filter_map(|p| p.estimate.as_ref());
+  let total: u64 = sizes.clone().map(|e| e.bytes).sum();
+  let largest = sizes.map(|e| e.bytes).max().unwrap_or(0);
+  total + largest * if mux { 2 } else { 1 }
+}
+
+// Compares the resolved episodes of the plan against the free space where
+// segments are staged and episodes are written (both under the working
+// directory). Fails when it does not fit, unless `force`.
+pub fn check_space(
+  plans: &[Planned],
+  mux: bool,
+  force: bool,
+) -> AsyncResult<()> {
+  let plans: Vec<&Planned> =
+    plans.iter().filter(|p| p.stream.is_ok()).collect();
+  let unknown = plans.iter().filter(|p| p.estimate.is_none()).count();
+  if unknown > 0 {
+    warn!("{} episodes without an estimate are not counted", unknown);
+  }
+
+  let needed = space_needed(&plans, mux);
+  let free = fs2::available_space(Path::new("."))?;
+  let summary = format!(
+    "{} episodes need about {}, {} free",
+    plans.len(),
+    format_size(needed),
+    format_size(free)
+  );
+
+  if needed > free {
+    if force {
+      warn!("{}, downloading anyway", summary);
+      return Ok(());
+    }
+    return Err(format!("{}, use --force to download anyway", summary).into());
+  }
+  if needed as f64 > free as f64 * (1.0 - SPACE_MARGIN) {
+    warn!("{}, that is tight", summary);
+  } else {
+    info!("{}", summary);
+  }
+  Ok(())
+}
@@ -0,0 +1,195 @@
+use std::{
+  collections::HashMap,
+  error::Error,
+  io,
+  sync::{Mutex, RwLock},
+  time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use reqwest::{header, Response, StatusCode, Url};
+
+use crate::api;
+
+pub const DEFAULT_PAGE_RATE: f64 = 2.0;
+pub const DEFAULT_SEGMENT_RATE: f64 = 20.0;
+// a slowed down host never goes below this many requests per second
+const MIN_RATE: f64 = 0.2;
+// successes needed to climb from the floor back to the configured rate
+const RECOVERY_STEPS: f64 = 20.0;
+// pause after a 429 without a usable Retry-After
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(5);
+
+// Requests per second for the site's html pages and for everything else
+// (the video cdn). Zero or less turns the limit off.
+#[derive(Clone, Copy)]
+pub struct Rates {
+  pub pages: f64,
+  pub segments: f64,
+}
+
+impl Default for Rates {
+  fn default() -> Rates {
+    Rates {
+      pages: DEFAULT_PAGE_RATE,
+      segments: DEFAULT_SEGMENT_RATE,
+    }
+  }
+}
+
+struct Bucket {
+  // what the host is allowed when it behaves
+  limit: f64,
+  // current rate, lowered on 429s and resets
+  rate: f64,
+  tokens: f64,
+  refilled: Instant,
+  paused_until: Option<Instant>,
+}
+
+impl Bucket {
+  fn new(limit: f64) -> Bucket {
+    Bucket {
+      limit,
+      rate: limit,
+      tokens: limit.max(1.0),
+      refilled: Instant::now(),
+      paused_until: None,
+    }
+  }
+
+  // Takes a token, or says how long to wait for one
+  fn take(&mut self) -> Option<Duration> {
+    let now = Instant::now();
+    if let Some(until) = self.paused_until {
+      if until > now {
+        return Some(until - now);
+      }
+      self.paused_until = None;
+    }
+
+    let elapsed = now.duration_since(self.refilled).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+    self.refilled = now;
+
+    if self.tokens >= 1.0 {
+      self.tokens -= 1.0;
+      None
+    } else {
+      Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+    }
+  }
+
+  fn slow_down(&mut self, pause: Option<Duration>) {
+    self.rate = (self.rate / 2.0).max(MIN_RATE);
+    self.tokens = self.tokens.min(0.0);
+    if let Some(pause) = pause {
+      self.paused_until = Some(Instant::now() + pause);
+    }
+  }
+
+  fn recover(&mut self) {
+    if self.rate < self.limit {
+      self.rate = (self.rate + self.limit / RECOVERY_STEPS).min(self.limit);
+    }
+  }
+}
+
+static RATES: RwLock<Option<Rates>> = RwLock::new(None);
+static BUCKETS: Mutex<Option<HashMap<String, Bucket>>> = Mutex::new(None);
+
+pub fn configure(rates: Rates) {
+  *RATES.write().unwrap() = Some(rates);
+  *BUCKETS.lock().unwrap() = None;
+}
+
+fn bucket_key(url: &Url) -> String {
+  url.origin().ascii_serialization()
+}
+
+fn limit_for(key: &str) -> f64 {
+  let rates = RATES.read().unwrap().unwrap_or_default();
+  if api::hosts().iter().any(|host| host == key) {
+    rates.pages
+  } else {
+    rates.segments
+  }
+}
+
+fn with_bucket<T>(url: &Url, f: impl FnOnce(&mut Bucket) -> T) -> Option<T> {
+  let key = bucket_key(url);
+  let limit = limit_for(&key);
+  if limit <= 0.0 {
+    return None;
+  }
+
+  let mut buckets = BUCKETS.lock().unwrap();
+  let bucket = buckets
+    .get_or_insert_with(HashMap::new)
+    .entry(key)
+    .or_insert_with(|| Bucket::new(limit));
+  Some(f(bucket))
+}
+
+// Waits until the host of `url` has budget for another request
+pub async fn acquire(url: &Url) {
+  while let Some(wait) = with_bucket(url, Bucket::take).flatten() {
+    tokio::time::sleep(wait).await;
+  }
+}
+
+fn retry_after(res: &Response) -> Option<Duration> {
+  let value = res.headers().get(header::RETRY_AFTER)?.to_str().ok()?;
+  value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+fn is_connection_reset(e: &reqwest::Error) -> bool {
+  let mut source = e.source();
+  while let Some(err) = source {
+    if let Some(io_error) = err.downcast_ref::<io::Error>() {
+      return matches!(
+        io_error.kind(),
+        io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+      );
+    }
+    source = err.source();
+  }
+  false
+}
+
+// Halves the host's rate on 429s and connection resets, and slowly climbs
+// back to the configured rate on every other answer
+pub fn record(url: &Url, result: &reqwest::Result<Response>) {
+  let pause = match result {
+    Ok(res) if res.status() == StatusCode::TOO_MANY_REQUESTS => {
+      Some(retry_after(res).unwrap_or(DEFAULT_BACKOFF))
+    }
+    Err(e) if is_connection_reset(e) => None,
+    Ok(_) => {
+      with_bucket(url, Bucket::recover);
+      return;
+    }
+    Err(_) => return,
+  };
+
+  let rate = with_bucket(url, |bucket| {
+    bucket.slow_down(pause);
+    bucket.rate
+  });
+  match (rate, pause) {
+    (Some(rate), Some(pause)) => warn!(
+      "{} is rate limiting, pausing {}s and slowing to {:.1} req/s",
+      url.host_str().unwrap_or(""),
+      pause.as_secs(),
+      rate
+    ),
+    (Some(rate), None) => warn!(
+      "{} reset the connection, slowing to {:.1} req/s",
+      url.host_str().unwrap_or(""),
+      rate
+    ),
+    (None, _) => {
+      info!("{} answered 429, rate limit is off", url)
+    }
+  }
+}
@@ -0,0 +1,365 @@
+use std::{
+  collections::BTreeSet,
+  fs::{self, File},
+  io::{self, Read, Seek, SeekFrom, Write},
+  path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+  api::{self, PlaylistTarget, ResolveOptions},
+  checksum, hls, verify, AsyncResult,
+};
+
+const MAP_SUFFIX: &str = ".segments.json";
+
+#[derive(Serialize, Deserialize)]
+pub struct MappedSegment {
+  // index in the (clipped) playlist
+  pub index: usize,
+  // where the segment's bytes sit in the combined file
+  pub offset: u64,
+  pub length: u64,
+  pub duration: f64,
+  pub url: String,
+  pub sha256: String,
+}
+
+// Where every segment of a combined file came from and ended up, kept next
+// to it as <file>.segments.json with --segment-map
+#[derive(Serialize, Deserialize)]
+pub struct SegmentMap {
+  pub episode_url: String,
+  pub referer: String,
+  pub quality: Option<String>,
+  pub target: PlaylistTarget,
+  pub clip: Option<(f64, f64)>,
+  // bytes of the init section in front of the first segment
+  pub init_length: u64,
+  pub segments: Vec<MappedSegment>,
+}
+
+pub fn sha256(bytes: &[u8]) -> String {
+  format!("{:x}", Sha256::digest(bytes))
+}
+
+impl SegmentMap {
+  pub fn path_for(file: &Path) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(MAP_SUFFIX);
+    PathBuf::from(name)
+  }
+
+  pub fn load(file: &Path) -> Option<SegmentMap> {
+    let content = fs::read_to_string(SegmentMap::path_for(file)).ok()?;
+    serde_json::from_str(&content).ok()
+  }
+
+  pub fn save(&self, file: &Path) -> io::Result<()> {
+    fs::write(SegmentMap::path_for(file), serde_json::to_string(self)?)
+  }
+
+  fn duration(&self) -> f64 {
+    self.segments.iter().map(|s| s.duration).sum()
+  }
+
+  // position of the segment playing at `time` seconds
+  fn segment_at(&self, time: f64) -> Option<usize> {
+    hls::segment_at(self.durations(), time)
+  }
+
+  fn durations(&self) -> impl ExactSizeIterator<Item = f64> + '_ {
+    self.segments.iter().map(|s| s.duration)
+  }
+}
+
+// Positions in the map of segments whose bytes no longer match their hash,
+// cut off ones included
+pub fn changed_segments(
+  path: &Path,
+  map: &SegmentMap,
+) -> io::Result<BTreeSet<usize>> {
+  let mut changed = BTreeSet::new();
+  let mut file = File::open(path)?;
+  let file_length = file.metadata()?.len();
+
+  for (position, segment) in map.segments.iter().enumerate() {
+    if segment.offset + segment.length > file_length {
+      changed.insert(position);
+      continue;
+    }
+    let mut bytes = vec![0; segment.length as usize];
+    file.seek(SeekFrom::Start(segment.offset))?;
+    file.read_exact(&mut bytes)?;
+    if sha256(&bytes) != segment.sha256 {
+      changed.insert(position);
+    }
+  }
+  Ok(changed)
+}
+
+// Positions in the map of segments whose bytes changed since the download
+// or that sit at a timestamp gap
+fn find_bad_segments(
+  path: &Path,
+  map: &SegmentMap,
+) -> AsyncResult<BTreeSet<usize>> {
+  let mut bad = changed_segments(path, map)?;
+
+  if path.extension().is_some_and(|e| e == "ts") {
+    let report = verify::verify_ts(path, Some(map.duration()))?;
+    // the gap starts at the last good timestamp, the bad data may be in
+    // that segment or the next
+    for gap in &report.gaps {
+      if let Some(position) = map.segment_at(gap.at) {
+        bad.insert(position);
+        if position + 1 < map.segments.len() {
+          bad.insert(position + 1);
+        }
+      }
+    }
+  }
+  Ok(bad)
+}
+
+// Writes `replacements` (position, bytes) over the old segments. Same sized
+// segments are written in place, otherwise the file is rebuilt and the
+// offsets after the first change move.
+fn splice(
+  path: &Path,
+  map: &mut SegmentMap,
+  replacements: Vec<(usize, Vec<u8>)>,
+) -> io::Result<()> {
+  let in_place = replacements.iter().all(|(position, bytes)| {
+    bytes.len() as u64 == map.segments[*position].length
+  });
+
+  if in_place {
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    for (position, bytes) in &replacements {
+      let segment = &mut map.segments[*position];
+      file.seek(SeekFrom::Start(segment.offset))?;
+      file.write_all(bytes)?;
+      segment.sha256 = sha256(bytes);
+    }
+    return Ok(());
+  }
+
+  let mut rebuilt = path.as_os_str().to_owned();
+  rebuilt.push(".repair");
+  let rebuilt = PathBuf::from(rebuilt);
+  let mut old = File::open(path)?;
+  let mut out = File::create(&rebuilt)?;
+  io::copy(&mut (&mut old).take(map.init_length), &mut out)?;
+
+  let mut replacements = replacements.into_iter().peekable();
+  let mut offset = map.init_length;
+  for (position, segment) in map.segments.iter_mut().enumerate() {
+    match replacements.next_if(|(p, _)| *p == position) {
+      Some((_, bytes)) => {
+        out.write_all(&bytes)?;
+        segment.length = bytes.len() as u64;
+        segment.sha256 = sha256(&bytes);
+      }
+      None => {
+        old.seek(SeekFrom::Start(segment.offset))?;
+        io::copy(&mut (&mut old).take(segment.length), &mut out)?;
+      }
+    }
+    segment.offset = offset;
+    offset += segment.length;
+  }
+
+  fs::rename(&rebuilt, path)
+}
+
+// Finds the corrupted segments of a file downloaded with --segment-map,
+// re-resolves the episode, fetches only those segments again and splices
+// them into place. Returns how many segments were replaced.
+pub async fn repair_file(
+  client: &Client,
+  path: &Path,
+  options: &ResolveOptions,
+) -> AsyncResult<usize> {
+  let Some(mut map) = SegmentMap::load(path) else {
+    return Err(
+      format!(
+        "no segment map for {}, download it with --segment-map",
+        path.display()
+      )
+      .into(),
+    );
+  };
+
+  let bad = find_bad_segments(path, &map)?;
+  if bad.is_empty() {
+    info!("{} has no bad segments", path.display());
+    return Ok(0);
+  }
+  info!(
+    "re-fetching segments {:?}",
+    bad
+      .iter()
+      .map(|&p| map.segments[p].index)
+      .collect::<Vec<usize>>()
+  );
+
+  let resolve = options.pinned(&map.quality);
+  let playlist = api::resolve_playlist(
+    client,
+    &map.episode_url,
+    &map.referer,
+    &resolve,
+    &map.target,
+    map.clip,
+  )
+  .await?;
+
+  let mut replacements = Vec::new();
+  for &position in &bad {
+    let fresh_index = playlist.map_index(map.durations(), position);
+    let Some(fresh) = fresh_index.and_then(|i| playlist.segments.get(i)) else {
+      return Err(
+        format!(
+          "segment {} missing from the playlist",
+          map.segments[position].index
+        )
+        .into(),
+      );
+    };
+
+    let bytes =
+      api::fetch_segment_bytes(client, &fresh.url, fresh.byte_range.as_ref())
+        .await?;
+    let segment = &mut map.segments[position];
+    if sha256(&bytes) != segment.sha256 {
+      warn!("segment {} differs from the one downloaded", segment.index);
+    }
+    segment.url = fresh.url.clone();
+    replacements.push((position, bytes));
+  }
+
+  let replaced = replacements.len();
+  splice(path, &mut map, replacements)?;
+  map.save(path)?;
+  checksum::update(path)?;
+
+  if path.extension().is_some_and(|e| e == "ts") {
+    let report = verify::verify_ts(path, Some(map.duration()))?;
+    report.save(path)?;
+    if report.is_ok() {
+      info!("verified {:.1}s of {:.1}s", report.duration, map.duration());
+    } else {
+      report
+        .problems
+        .iter()
+        .for_each(|problem| warn!("{}: {}", path.display(), problem));
+    }
+  }
+  Ok(replaced)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::env;
+
+  use super::*;
+  use crate::verify::tests::{av_stream, FRAME};
+
+  // Writes `segments` one after the other and maps them, 0.8s each
+  fn mapped_file(name: &str, segments: &[Vec<u8>]) -> (PathBuf, SegmentMap) {
+    let path = env::temp_dir().join(format!("anilife-dl-repair-{}", name));
+    fs::write(&path, segments.concat()).unwrap();
+
+    let mut offset = 0;
+    let segments = segments
+      .iter()
+      .enumerate()
+      .map(|(index, bytes)| {
+        let segment = MappedSegment {
+          index,
+          offset,
+          length: bytes.len() as u64,
+          duration: 0.8,
+          url: format!("http://video.example/seg{}.ts", index),
+          sha256: sha256(bytes),
+        };
+        offset += bytes.len() as u64;
+        segment
+      })
+      .collect();
+    let map = SegmentMap {
+      episode_url: "http://anime.example/ep/1".to_string(),
+      referer: "http://anime.example".to_string(),
+      quality: None,
+      target: PlaylistTarget::Media,
+      clip: None,
+      init_length: 0,
+      segments,
+    };
+    (path, map)
+  }
+
+  fn plain_segments() -> Vec<Vec<u8>> {
+    (0..4u8).map(|index| vec![index; 100]).collect()
+  }
+
+  fn positions(set: BTreeSet<usize>) -> Vec<usize> {
+    set.into_iter().collect()
+  }
+
+  #[test]
+  fn one_segment_changed_in_place() {
+    let (path, mut map) = mapped_file("in-place", &plain_segments());
+    let mut content = fs::read(&path).unwrap();
+    content[150] ^= 0xff;
+    fs::write(&path, &content).unwrap();
+    assert_eq!(positions(changed_segments(&path, &map).unwrap()), [1]);
+
+    splice(&path, &mut map, vec![(1, vec![1; 100])]).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), plain_segments().concat());
+    assert!(changed_segments(&path, &map).unwrap().is_empty());
+    let offsets: Vec<u64> = map.segments.iter().map(|s| s.offset).collect();
+    assert_eq!(offsets, [0, 100, 200, 300]);
+  }
+
+  #[test]
+  fn changed_length_rebuilds() {
+    let (path, mut map) = mapped_file("rebuild", &plain_segments());
+    // cut off in the middle of the last segment
+    let content = fs::read(&path).unwrap();
+    fs::write(&path, &content[..350]).unwrap();
+    assert_eq!(positions(changed_segments(&path, &map).unwrap()), [3]);
+
+    splice(&path, &mut map, vec![(1, vec![9; 40]), (3, vec![3; 120])]).unwrap();
+    let expected =
+      [vec![0; 100], vec![9; 40], vec![2; 100], vec![3; 120]].concat();
+    assert_eq!(fs::read(&path).unwrap(), expected);
+    assert!(changed_segments(&path, &map).unwrap().is_empty());
+    let layout: Vec<(u64, u64)> =
+      map.segments.iter().map(|s| (s.offset, s.length)).collect();
+    assert_eq!(layout, [(0, 100), (100, 40), (140, 100), (240, 120)]);
+  }
+
+  #[test]
+  fn timestamp_gap_marks_both_sides() {
+    // four segments of two frames, three seconds missing after the second
+    let mut muxer = av_stream();
+    let mut segments = Vec::new();
+    let mut start = 0;
+    for segment in 0..4 {
+      let skipped = if segment >= 2 { 3 * 90_000 } else { 0 };
+      muxer.frames((0..2).map(|f| (segment * 2 + f) * FRAME + skipped));
+      segments.push(muxer.out[start..].to_vec());
+      start = muxer.out.len();
+    }
+    let (path, map) = mapped_file("gap.ts", &segments);
+
+    let bad = find_bad_segments(&path, &map).unwrap();
+    assert_eq!(positions(bad), [1, 2]);
+  }
+}
@@ -0,0 +1,287 @@
+use std::{
+  env, fs, io,
+  path::PathBuf,
+  sync::{Arc, Mutex},
+};
+
+use chacha20poly1305::{
+  aead::{Aead, AeadCore, KeyInit, OsRng},
+  ChaCha20Poly1305, Key, Nonce,
+};
+use log::{info, warn};
+use reqwest::{
+  cookie::{CookieStore, Jar},
+  Client, StatusCode, Url,
+};
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+
+use crate::{api, config, http, AsyncResult};
+
+const LOGIN_PATH: &str = "/login";
+const LOGOUT_PATH: &str = "/logout";
+const SESSION_FILE: &str = "session.bin";
+const KEY_FILE: &str = "session.key";
+const NONCE_LEN: usize = 12;
+pub const PASSWORD_ENV: &str = "ANILIFE_PASSWORD";
+
+// What is kept on disk, encrypted. The password never is, logging in again
+// after the site expires the session needs $ANILIFE_PASSWORD.
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+  host: String,
+  cookies: String,
+  username: String,
+}
+
+struct ActiveSession {
+  jar: Arc<Jar>,
+  username: String,
+}
+
+static JAR: Mutex<Option<Arc<Jar>>> = Mutex::new(None);
+static ACTIVE: Mutex<Option<ActiveSession>> = Mutex::new(None);
+
+fn session_path() -> PathBuf {
+  config::config_dir().join(SESSION_FILE)
+}
+
+// The key lives next to the session so it only guards against the session
+// file being copied or backed up on its own
+fn cipher() -> io::Result<ChaCha20Poly1305> {
+  let path = config::config_dir().join(KEY_FILE);
+  if let Ok(key) = fs::read(&path) {
+    if key.len() == 32 {
+      return Ok(ChaCha20Poly1305::new(Key::from_slice(&key)));
+    }
+  }
+
+  let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+  fs::create_dir_all(config::config_dir())?;
+  fs::write(&path, key)?;
+  restrict_permissions(&path)?;
+  Ok(ChaCha20Poly1305::new(&key))
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &PathBuf) -> io::Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+  fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &PathBuf) -> io::Result<()> {
+  Ok(())
+}
+
+fn save(session: &StoredSession) -> AsyncResult<()> {
+  let plain = serde_json::to_vec(session)?;
+  let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+  let encrypted = cipher()?
+    .encrypt(&nonce, plain.as_slice())
+    .map_err(|_| "unable to encrypt session")?;
+
+  let path = session_path();
+  fs::write(&path, [nonce.as_slice(), &encrypted].concat())?;
+  restrict_permissions(&path)?;
+  Ok(())
+}
+
+fn load() -> Option<StoredSession> {
+  let data = fs::read(session_path()).ok()?;
+  if data.len() <= NONCE_LEN {
+    return None;
+  }
+
+  let (nonce, encrypted) = data.split_at(NONCE_LEN);
+  let plain = match cipher().ok()?.decrypt(Nonce::from_slice(nonce), encrypted)
+  {
+    Ok(p) => p,
+    Err(_) => {
+      warn!("stored session can not be decrypted, log in again");
+      return None;
+    }
+  };
+  serde_json::from_slice(&plain).ok()
+}
+
+// Registers the client's cookie jar and puts a stored session into it
+pub fn restore(jar: Arc<Jar>) {
+  *JAR.lock().unwrap() = Some(jar.clone());
+
+  let Some(stored) = load() else { return };
+  let Ok(url) = Url::parse(&stored.host) else {
+    return;
+  };
+  stored
+    .cookies
+    .split(';')
+    .map(|cookie| cookie.trim())
+    .filter(|cookie| !cookie.is_empty())
+    .for_each(|cookie| jar.add_cookie_str(cookie, &url));
+
+  info!("using session of {}", stored.username);
+  *ACTIVE.lock().unwrap() = Some(ActiveSession {
+    jar,
+    username: stored.username,
+  });
+}
+
+struct LoginForm {
+  action: String,
+  fields: Vec<(String, String)>,
+  username_field: String,
+  password_field: String,
+}
+
+// The first form with a password input, hidden inputs (csrf tokens) included
+fn find_login_form(html: &str) -> Option<LoginForm> {
+  let document = Html::parse_document(html);
+  let form_selector = Selector::parse("form").unwrap();
+  let input_selector = Selector::parse("input[name]").unwrap();
+
+  document.select(&form_selector).find_map(|form| {
+    let mut fields = Vec::new();
+    let mut username_field = None;
+    let mut password_field = None;
+
+    for input in form.select(&input_selector) {
+      let name = input.value().attr("name").unwrap_or("").to_string();
+      let value = input.value().attr("value").unwrap_or("").to_string();
+      match input.value().attr("type").unwrap_or("text") {
+        "password" => {
+          password_field.get_or_insert(name);
+        }
+        "text" | "email" => {
+          username_field.get_or_insert(name);
+        }
+        "hidden" => fields.push((name, value)),
+        _ => {}
+      }
+    }
+
+    Some(LoginForm {
+      action: form.value().attr("action").unwrap_or("").to_string(),
+      fields,
+      username_field: username_field?,
+      password_field: password_field?,
+    })
+  })
+}
+
+fn is_login_url(url: &Url) -> bool {
+  url.path().starts_with(LOGIN_PATH)
+}
+
+async fn submit_login(
+  client: &Client,
+  username: &str,
+  password: &str,
+) -> AsyncResult<()> {
+  let req = client.get(api::build_url(LOGIN_PATH));
+  let res = http::send(client, req).await?;
+  let page_url = res.url().clone();
+  let html = http::read_text(res).await?;
+  let Some(form) = find_login_form(&html) else {
+    return Err(format!("no login form found at {}", page_url).into());
+  };
+
+  let mut params = form.fields;
+  params.push((form.username_field, username.to_string()));
+  params.push((form.password_field, password.to_string()));
+  let action = page_url.join(&form.action)?;
+
+  let req = client
+    .post(action)
+    .header("Referer", page_url.as_str())
+    .form(&params);
+  let res = http::send(client, req).await?;
+  let status = res.status();
+  let url = res.url().clone();
+  let html = http::read_text(res).await?;
+
+  if status.is_client_error()
+    || (is_login_url(&url) && find_login_form(&html).is_some())
+  {
+    return Err("login failed, check username and password".into());
+  }
+  Ok(())
+}
+
+fn store(jar: &Jar, username: &str) -> AsyncResult<()> {
+  let host = api::host();
+  let cookies = jar
+    .cookies(&Url::parse(&host)?)
+    .and_then(|c| c.to_str().ok().map(|c| c.to_string()))
+    .unwrap_or_default();
+  if cookies.is_empty() {
+    warn!("login succeeded but the site set no cookies");
+  }
+
+  save(&StoredSession {
+    host,
+    cookies,
+    username: username.to_string(),
+  })
+}
+
+pub async fn login(
+  client: &Client,
+  username: &str,
+  password: &str,
+) -> AsyncResult<()> {
+  let Some(jar) = JAR.lock().unwrap().clone() else {
+    return Err("http client has no cookie jar".into());
+  };
+
+  submit_login(client, username, password).await?;
+  store(&jar, username)?;
+  info!("logged in as {}", username);
+
+  *ACTIVE.lock().unwrap() = Some(ActiveSession {
+    jar,
+    username: username.to_string(),
+  });
+  Ok(())
+}
+
+pub async fn logout(client: &Client) -> AsyncResult<()> {
+  if ACTIVE.lock().unwrap().take().is_some() {
+    let req = client.get(api::build_url(LOGOUT_PATH));
+    if let Err(e) = http::send(client, req).await {
+      warn!("logout request failed: {}", e);
+    }
+  }
+
+  match fs::remove_file(session_path()) {
+    Ok(()) => info!("session removed"),
+    Err(e) if e.kind() == io::ErrorKind::NotFound => info!("not logged in"),
+    Err(e) => return Err(e.into()),
+  }
+  Ok(())
+}
+
+// A logged in request that lands on the login page (or gets a 401) means the
+// site expired the session
+pub fn is_expired(url: &str, status: StatusCode) -> bool {
+  ACTIVE.lock().unwrap().is_some()
+    && (status == StatusCode::UNAUTHORIZED
+      || Url::parse(url).is_ok_and(|url| is_login_url(&url)))
+}
+
+// Logs in again as the stored user with $ANILIFE_PASSWORD
+pub async fn relogin(client: &Client) -> AsyncResult<()> {
+  let (jar, username) = match &*ACTIVE.lock().unwrap() {
+    Some(s) => (s.jar.clone(), s.username.clone()),
+    None => return Err("not logged in".into()),
+  };
+  let Ok(password) = env::var(PASSWORD_ENV) else {
+    return Err(
+      format!("session expired, log in again with --login {}", username).into(),
+    );
+  };
+
+  warn!("session expired, logging in again as {}", username);
+  submit_login(client, &username, &password).await?;
+  store(&jar, &username)
+}
@@ -0,0 +1,567 @@
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  fs::{self, File},
+  io::{self, BufReader, Read},
+  path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::AsyncResult;
+
+const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const NULL_PID: u16 = 0x1fff;
+const PTS_HZ: f64 = 90_000.0;
+const PTS_WRAP: i64 = 1 << 33;
+// timestamps further apart than this within one stream are a gap
+const MAX_GAP: f64 = 1.0;
+// how far the measured duration may be off from the playlist's
+const DURATION_TOLERANCE: f64 = 2.0;
+const REPORT_SUFFIX: &str = ".verify.json";
+
+#[derive(Serialize, Deserialize)]
+pub struct Gap {
+  pub pid: u16,
+  // seconds from the start of the stream
+  pub at: f64,
+  pub length: f64,
+}
+
+// What verify found in a TS file, kept next to it as <file>.verify.json
+#[derive(Serialize, Deserialize)]
+pub struct Report {
+  // sum of the playlist's #EXTINF durations, when known
+  pub expected_duration: Option<f64>,
+  // from the PTS of the main stream
+  pub duration: f64,
+  pub packets: u64,
+  pub sync_errors: u64,
+  // only informational, some packagers restart the counters every segment
+  pub continuity_errors: u64,
+  // declared in the PMT but never seen
+  pub missing_pids: Vec<u16>,
+  pub gaps: Vec<Gap>,
+  pub problems: Vec<String>,
+}
+
+impl Report {
+  pub fn is_ok(&self) -> bool {
+    self.problems.is_empty()
+  }
+
+  pub fn path_for(file: &Path) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(REPORT_SUFFIX);
+    PathBuf::from(name)
+  }
+
+  pub fn load(file: &Path) -> Option<Report> {
+    let content = fs::read_to_string(Report::path_for(file)).ok()?;
+    serde_json::from_str(&content).ok()
+  }
+
+  pub fn save(&self, file: &Path) -> io::Result<()> {
+    fs::write(Report::path_for(file), serde_json::to_string_pretty(self)?)
+  }
+}
+
+#[derive(Default)]
+struct PidState {
+  continuity: Option<u8>,
+  payload_packets: u64,
+  first_pts: Option<i64>,
+  last_pts: i64,
+  // unwrapped, relative to the first PTS
+  pts: i64,
+  min_pts: i64,
+  max_pts: i64,
+}
+
+#[derive(Default)]
+struct Scan {
+  packets: u64,
+  sync_errors: u64,
+  continuity_errors: u64,
+  pmt_pids: BTreeSet<u16>,
+  // elementary stream pid -> stream type
+  streams: BTreeMap<u16, u8>,
+  pids: BTreeMap<u16, PidState>,
+  gaps: Vec<Gap>,
+}
+
+fn is_video(stream_type: u8) -> bool {
+  matches!(stream_type, 0x01 | 0x02 | 0x10 | 0x1b | 0x24)
+}
+
+// The section of a PSI packet payload, after the pointer field
+fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+  let pointer = *payload.first()? as usize;
+  let section = payload.get(1 + pointer..)?;
+  let length =
+    ((section.get(1)? & 0x0f) as usize) << 8 | *section.get(2)? as usize;
+  // without the crc
+  section.get(..(3 + length).checked_sub(4)?)
+}
+
+fn pes_pts(payload: &[u8]) -> Option<i64> {
+  if payload.len() < 14 || payload[..3] != [0, 0, 1] || payload[7] & 0x80 == 0 {
+    return None;
+  }
+  let p = &payload[9..14];
+  Some(
+    ((p[0] as i64 >> 1) & 0x07) << 30
+      | (p[1] as i64) << 22
+      | (p[2] as i64 >> 1) << 15
+      | (p[3] as i64) << 7
+      | p[4] as i64 >> 1,
+  )
+}
+
+impl Scan {
+  fn packet(&mut self, packet: &[u8]) {
+    self.packets += 1;
+    let pid = ((packet[1] & 0x1f) as u16) << 8 | packet[2] as u16;
+    if pid == NULL_PID {
+      return;
+    }
+    let unit_start = packet[1] & 0x40 != 0;
+    let adaptation = packet[3] & 0x20 != 0;
+    let has_payload = packet[3] & 0x10 != 0;
+    let continuity = packet[3] & 0x0f;
+
+    let mut offset = 4;
+    let mut discontinuity = false;
+    if adaptation {
+      let length = packet[4] as usize;
+      discontinuity = length > 0 && packet[5] & 0x80 != 0;
+      offset += 1 + length;
+    }
+    if !has_payload || offset >= PACKET_SIZE {
+      return;
+    }
+    let payload = &packet[offset..];
+
+    let state = self.pids.entry(pid).or_default();
+    state.payload_packets += 1;
+    if let Some(last) = state.continuity {
+      // a repeated counter is an allowed duplicate packet
+      let expected = (last + 1) & 0x0f;
+      if !discontinuity && continuity != expected && continuity != last {
+        self.continuity_errors += 1;
+      }
+    }
+    state.continuity = Some(continuity);
+
+    if !unit_start {
+      return;
+    }
+    if pid == 0 {
+      self.parse_pat(payload);
+    } else if self.pmt_pids.contains(&pid) {
+      self.parse_pmt(payload);
+    } else if self.streams.contains_key(&pid) {
+      if let Some(pts) = pes_pts(payload) {
+        self.timestamp(pid, pts);
+      }
+    }
+  }
+
+  fn parse_pat(&mut self, payload: &[u8]) {
+    let Some(section) = psi_section(payload) else {
+      return;
+    };
+    for program in section.get(8..).unwrap_or(&[]).chunks_exact(4) {
+      let number = (program[0] as u16) << 8 | program[1] as u16;
+      if number != 0 {
+        self
+          .pmt_pids
+          .insert(((program[2] & 0x1f) as u16) << 8 | program[3] as u16);
+      }
+    }
+  }
+
+  fn parse_pmt(&mut self, payload: &[u8]) {
+    let Some(section) = psi_section(payload) else {
+      return;
+    };
+    let Some(info) = section.get(10..12) else {
+      return;
+    };
+    let info_length = ((info[0] & 0x0f) as usize) << 8 | info[1] as usize;
+
+    let mut entries = section.get(12 + info_length..).unwrap_or(&[]);
+    while entries.len() >= 5 {
+      let stream_type = entries[0];
+      let pid = ((entries[1] & 0x1f) as u16) << 8 | entries[2] as u16;
+      let es_info_length =
+        ((entries[3] & 0x0f) as usize) << 8 | entries[4] as usize;
+      self.streams.insert(pid, stream_type);
+      entries = entries.get(5 + es_info_length..).unwrap_or(&[]);
+    }
+  }
+
+  fn timestamp(&mut self, pid: u16, pts: i64) {
+    let state = self.pids.entry(pid).or_default();
+    if state.first_pts.is_none() {
+      state.first_pts = Some(pts);
+      state.last_pts = pts;
+      return;
+    }
+
+    // signed difference, PTS wraps at 2^33
+    let mut diff = (pts - state.last_pts).rem_euclid(PTS_WRAP);
+    if diff > PTS_WRAP / 2 {
+      diff -= PTS_WRAP;
+    }
+    state.last_pts = pts;
+    state.pts += diff;
+    state.min_pts = state.min_pts.min(state.pts);
+    state.max_pts = state.max_pts.max(state.pts);
+
+    let length = diff.abs() as f64 / PTS_HZ;
+    if length > MAX_GAP {
+      self.gaps.push(Gap {
+        pid,
+        at: (state.pts - diff) as f64 / PTS_HZ,
+        length,
+      });
+    }
+  }
+
+  // the first video stream, or the first stream at all
+  fn main_pid(&self) -> Option<u16> {
+    self
+      .streams
+      .iter()
+      .find(|(_, &stream_type)| is_video(stream_type))
+      .or_else(|| self.streams.iter().next())
+      .map(|(&pid, _)| pid)
+  }
+
+  fn into_report(self, expected_duration: Option<f64>) -> Report {
+    let mut problems = Vec::new();
+    if self.packets == 0 {
+      problems.push("no transport stream packets".to_string());
+    }
+    if self.sync_errors > 0 {
+      problems.push(format!("{} sync errors", self.sync_errors));
+    }
+    if self.packets > 0 && self.streams.is_empty() {
+      problems.push("no PAT/PMT found".to_string());
+    }
+
+    let missing_pids: Vec<u16> = self
+      .streams
+      .keys()
+      .filter(|pid| {
+        self
+          .pids
+          .get(pid)
+          .is_none_or(|state| state.payload_packets == 0)
+      })
+      .copied()
+      .collect();
+    if !missing_pids.is_empty() {
+      problems.push(format!("streams {:?} have no data", missing_pids));
+    }
+
+    let duration = self
+      .main_pid()
+      .and_then(|pid| self.pids.get(&pid))
+      .map(|state| (state.max_pts - state.min_pts) as f64 / PTS_HZ)
+      .unwrap_or(0.0);
+    if let Some(expected) = expected_duration {
+      if (duration - expected).abs() > DURATION_TOLERANCE {
+        problems.push(format!(
+          "duration {:.1}s, playlist says {:.1}s",
+          duration, expected
+        ));
+      }
+    }
+    for gap in &self.gaps {
+      problems.push(format!(
+        "{:.1}s gap at {:.1}s in stream {}",
+        gap.length, gap.at, gap.pid
+      ));
+    }
+
+    Report {
+      expected_duration,
+      duration,
+      packets: self.packets,
+      sync_errors: self.sync_errors,
+      continuity_errors: self.continuity_errors,
+      missing_pids,
+      gaps: self.gaps,
+      problems,
+    }
+  }
+}
+
+// Reads one packet, skipping ahead to the next sync byte when lost
+fn read_packet(
+  reader: &mut impl Read,
+  packet: &mut [u8; PACKET_SIZE],
+  scan: &mut Scan,
+) -> io::Result<bool> {
+  let mut first = [0u8; 1];
+  if reader.read(&mut first)? == 0 {
+    return Ok(false);
+  }
+  if first[0] != SYNC_BYTE {
+    scan.sync_errors += 1;
+    loop {
+      if reader.read(&mut first)? == 0 {
+        return Ok(false);
+      }
+      if first[0] == SYNC_BYTE {
+        break;
+      }
+    }
+  }
+
+  packet[0] = SYNC_BYTE;
+  match reader.read_exact(&mut packet[1..]) {
+    Ok(()) => Ok(true),
+    // a truncated last packet
+    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+      scan.sync_errors += 1;
+      Ok(false)
+    }
+    Err(e) => Err(e),
+  }
+}
+
+// Checks a TS file's packets, program tables and timestamps.
+// `expected_duration` is the playlist's total, if known.
+pub fn verify_ts(
+  path: &Path,
+  expected_duration: Option<f64>,
+) -> io::Result<Report> {
+  let reader = BufReader::with_capacity(PACKET_SIZE * 1024, File::open(path)?);
+  verify_stream(reader, expected_duration)
+}
+
+fn verify_stream(
+  mut reader: impl Read,
+  expected_duration: Option<f64>,
+) -> io::Result<Report> {
+  let mut scan = Scan::default();
+  let mut packet = [0u8; PACKET_SIZE];
+
+  while read_packet(&mut reader, &mut packet, &mut scan)? {
+    scan.packet(&packet);
+  }
+  Ok(scan.into_report(expected_duration))
+}
+
+fn collect_ts_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+  if path.is_dir() {
+    let mut entries: Vec<PathBuf> = fs::read_dir(path)?
+      .filter_map(|entry| entry.ok().map(|e| e.path()))
+      .collect();
+    entries.sort();
+    for entry in entries {
+      collect_ts_files(&entry, files)?;
+    }
+  } else if path.extension().is_some_and(|e| e == "ts") {
+    files.push(path.to_path_buf());
+  }
+  Ok(())
+}
+
+// Re-checks a file or every .ts under a directory, reusing the expected
+// duration of earlier reports. Returns how many files were checked and the
+// suspect ones with their reports.
+pub fn verify_library(
+  path: &Path,
+) -> AsyncResult<(usize, Vec<(PathBuf, Report)>)> {
+  let mut files = Vec::new();
+  collect_ts_files(path, &mut files)?;
+  if files.is_empty() {
+    return Err(format!("no .ts files in {}", path.display()).into());
+  }
+
+  let mut suspects = Vec::new();
+  for file in &files {
+    let expected = Report::load(file).and_then(|r| r.expected_duration);
+    let report = verify_ts(file, expected)?;
+    report.save(file)?;
+    if !report.is_ok() {
+      suspects.push((file.clone(), report));
+    }
+  }
+  Ok((files.len(), suspects))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+
+  const PMT_PID: u16 = 0x100;
+  const VIDEO_PID: u16 = 0x101;
+  const AUDIO_PID: u16 = 0x102;
+  // 0.4s between frames
+  pub(crate) const FRAME: i64 = 36_000;
+
+  // Builds transport stream packets the way a muxer lays them out
+  #[derive(Default)]
+  pub(crate) struct Muxer {
+    pub(crate) out: Vec<u8>,
+    continuity: BTreeMap<u16, u8>,
+  }
+
+  impl Muxer {
+    // one packet, padded with adaptation field stuffing
+    fn packet(&mut self, pid: u16, unit_start: bool, payload: &[u8]) {
+      let counter = self.continuity.entry(pid).or_default();
+      let mut header = vec![
+        SYNC_BYTE,
+        if unit_start { 0x40 } else { 0 } | (pid >> 8) as u8,
+        pid as u8,
+        0x10 | *counter,
+      ];
+      *counter = (*counter + 1) & 0x0f;
+      if payload.len() < PACKET_SIZE - 4 {
+        let stuffing = PACKET_SIZE - 5 - payload.len();
+        header[3] |= 0x20;
+        header.push(stuffing as u8);
+        if stuffing > 0 {
+          header.push(0);
+          header.extend(std::iter::repeat_n(0xff, stuffing - 1));
+        }
+      }
+      self.out.extend(header);
+      self.out.extend(payload);
+    }
+
+    fn section(&mut self, pid: u16, table_id: u8, body: &[u8]) {
+      let length = 5 + body.len() + 4;
+      let mut payload = vec![0, table_id, 0xb0, length as u8, 0, 1, 0xc1, 0, 0];
+      payload.extend(body);
+      // the crc is not checked
+      payload.extend([0; 4]);
+      self.packet(pid, true, &payload);
+    }
+
+    fn tables(&mut self, streams: &[(u8, u16)]) {
+      self.section(0, 0, &[0, 1, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8]);
+      let mut body =
+        vec![0xe0 | (VIDEO_PID >> 8) as u8, VIDEO_PID as u8, 0xf0, 0];
+      for &(stream_type, pid) in streams {
+        body.extend([stream_type, 0xe0 | (pid >> 8) as u8, pid as u8, 0xf0, 0]);
+      }
+      self.section(PMT_PID, 2, &body);
+    }
+
+    fn pes(&mut self, pid: u16, pts: i64) {
+      let pts = pts.rem_euclid(PTS_WRAP);
+      let mut payload = vec![0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5];
+      payload.extend([
+        0x21 | ((pts >> 29) & 0x0e) as u8,
+        (pts >> 22) as u8,
+        0x01 | ((pts >> 14) & 0xfe) as u8,
+        (pts >> 7) as u8,
+        0x01 | ((pts << 1) & 0xfe) as u8,
+      ]);
+      self.packet(pid, true, &payload);
+      self.packet(pid, false, &[0xaa; PACKET_SIZE - 4]);
+    }
+
+    // video and audio frames at `pts`
+    pub(crate) fn frames(&mut self, pts: impl IntoIterator<Item = i64>) {
+      for pts in pts {
+        self.pes(VIDEO_PID, pts);
+        self.pes(AUDIO_PID, pts);
+      }
+    }
+
+    fn verify(&self, expected_duration: Option<f64>) -> Report {
+      verify_stream(&self.out[..], expected_duration).unwrap()
+    }
+  }
+
+  pub(crate) fn av_stream() -> Muxer {
+    let mut muxer = Muxer::default();
+    muxer.tables(&[(0x1b, VIDEO_PID), (0x0f, AUDIO_PID)]);
+    muxer
+  }
+
+  fn approx(a: f64, b: f64) -> bool {
+    (a - b).abs() < 1e-6
+  }
+
+  #[test]
+  fn good_stream() {
+    let mut muxer = av_stream();
+    muxer.frames((0..10).map(|f| 90_000 + f * FRAME));
+
+    let report = muxer.verify(Some(3.6));
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert!(approx(report.duration, 3.6));
+    assert_eq!(report.packets, 42);
+    assert_eq!(report.sync_errors, 0);
+    assert_eq!(report.continuity_errors, 0);
+  }
+
+  #[test]
+  fn sync_loss() {
+    let mut muxer = av_stream();
+    muxer.frames((0..5).map(|f| f * FRAME));
+    muxer.out.extend([0; 100]);
+    muxer.frames((5..10).map(|f| f * FRAME));
+
+    let report = muxer.verify(None);
+    assert_eq!(report.sync_errors, 1);
+    assert_eq!(report.problems, ["1 sync errors"]);
+    assert!(approx(report.duration, 3.6));
+  }
+
+  #[test]
+  fn pmt_stream_without_data() {
+    let mut muxer = av_stream();
+    for f in 0..10 {
+      muxer.pes(VIDEO_PID, f * FRAME);
+    }
+
+    let report = muxer.verify(None);
+    assert_eq!(report.missing_pids, [AUDIO_PID]);
+    assert!(!report.is_ok());
+  }
+
+  #[test]
+  fn pts_wrap() {
+    let mut muxer = av_stream();
+    muxer.frames((0..10).map(|f| PTS_WRAP - 4 * FRAME + f * FRAME));
+
+    let report = muxer.verify(Some(3.6));
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert!(report.gaps.is_empty());
+    assert!(approx(report.duration, 3.6));
+  }
+
+  #[test]
+  fn gap_over_max_gap() {
+    let mut muxer = av_stream();
+    muxer.frames((0..5).map(|f| f * FRAME));
+    // three seconds missing after the fifth frame
+    muxer.frames((5..10).map(|f| f * FRAME + 3 * 90_000));
+
+    let report = muxer.verify(None);
+    let gaps: Vec<(u16, f64, f64)> = report
+      .gaps
+      .iter()
+      .map(|g| (g.pid, g.at, g.length))
+      .collect();
+    assert_eq!(gaps.len(), 2);
+    for (&(pid, at, length), expected) in
+      gaps.iter().zip([VIDEO_PID, AUDIO_PID])
+    {
+      assert_eq!(pid, expected);
+      assert!(approx(at, 1.6));
+      assert!(approx(length, 3.4));
+    }
+    assert!(!report.is_ok());
+  }
+}
@@ -1,11 +1,12 @@
 use std::{
   fs::{self},
-  io::{self},
+  io::{self, Write},
+  process::Command,
 };
 
 use log::{error, info};
 
-use crate::cli::print_progress;
+use crate::{cli::print_progress, hls::RenditionType};
 
 pub fn concat_ts() {
   info!("Combining...");
@@ -40,3 +41,111 @@ pub fn concat_ts() {
     }
   });
 }
+
+// WebVTT segments each carry their own header, keep only the first one
+pub fn concat_vtt(file_names: &[&str], out: &mut fs::File) -> io::Result<()> {
+  writeln!(out, "WEBVTT")?;
+
+  for file_name in file_names {
+    let content = fs::read_to_string(file_name)?;
+    let mut lines = content.lines().peekable();
+    while let Some(line) = lines.peek() {
+      if line.starts_with("WEBVTT") || line.starts_with("X-TIMESTAMP-MAP") {
+        lines.next();
+      } else {
+        break;
+      }
+    }
+
+    writeln!(out)?;
+    for line in lines {
+      writeln!(out, "{}", line)?;
+    }
+  }
+
+  Ok(())
+}
+
+// How many audio and subtitle streams `file` has, from ffmpeg's listing of
+// its input streams
+fn count_streams(file: &str) -> io::Result<(usize, usize)> {
+  let probe = Command::new("ffmpeg")
+    .args(["-hide_banner", "-i", file])
+    .output()?;
+  let listing = String::from_utf8_lossy(&probe.stderr);
+  let streams: Vec<&str> = listing
+    .lines()
+    .filter(|line| line.trim_start().starts_with("Stream #0:"))
+    .collect();
+  let count = |kind: &str| streams.iter().filter(|s| s.contains(kind)).count();
+  Ok((count(": Audio:"), count(": Subtitle:")))
+}
+
+// Muxes `video` with extra (file, language, kind) tracks into `output` using
+// ffmpeg
+pub fn mux(
+  video: &str,
+  tracks: &[(String, String, RenditionType)],
+  output: &str,
+) -> io::Result<()> {
+  info!("Muxing {} tracks into {}", tracks.len() + 1, output);
+
+  let mut command = Command::new("ffmpeg");
+  command.args(["-y", "-loglevel", "error", "-i", video]);
+  tracks.iter().for_each(|(file, _, _)| {
+    command.args(["-i", file]);
+  });
+  for index in 0..=tracks.len() {
+    command.args(["-map", &index.to_string()]);
+  }
+  command.args(["-c", "copy"]);
+  // the video may carry audio of its own, tracks come after its streams of
+  // the same type
+  let (mut audio, mut subs) = count_streams(video)?;
+  for (_, language, kind) in tracks {
+    let specifier = match kind {
+      RenditionType::Audio => {
+        audio += 1;
+        format!("a:{}", audio - 1)
+      }
+      _ => {
+        subs += 1;
+        format!("s:{}", subs - 1)
+      }
+    };
+    command.arg(format!("-metadata:s:{}", specifier));
+    command.arg(format!("language={}", language));
+  }
+  command.arg(output);
+
+  let status = command.status()?;
+  if !status.success() {
+    return Err(io::Error::other(format!("ffmpeg exited with {}", status)));
+  }
+
+  Ok(())
+}
+
+// Cuts `duration` seconds starting at `offset` out of `input` without
+// re-encoding, so both ends snap to the nearest keyframes
+pub fn trim(
+  input: &str,
+  offset: f64,
+  duration: f64,
+  output: &str,
+) -> io::Result<()> {
+  info!("Trimming {:.2}s from {:.2}s", duration, offset);
+
+  let status = Command::new("ffmpeg")
+    .args(["-y", "-loglevel", "error"])
+    .args(["-ss", &format!("{:.3}", offset)])
+    .args(["-i", input])
+    .args(["-t", &format!("{:.3}", duration)])
+    .args(["-map", "0", "-c", "copy", output])
+    .status()?;
+  if !status.success() {
+    return Err(io::Error::other(format!("ffmpeg exited with {}", status)));
+  }
+
+  Ok(())
+}